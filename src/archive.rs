@@ -0,0 +1,198 @@
+/* Members of tar and zip archives as items
+*
+*  Each regular file in an archive is an item of its own, named after the
+*  archive and its path in it, like "release.tar.gz:src/main.rs", so whether
+*  the files in a release were seen before can be asked without unpacking
+*  it.  Archives are told apart by what they start with rather than their
+*  names: zip, gzip (which must hold a tar) and anything else is tried as a
+*  plain tar.  Members are read into memory whole and handed over one at a
+*  time, and any over MAX_MEMBER_LEN once decompressed, or that take all of
+*  them together over MAX_TOTAL_LEN, fail the archive rather than take all
+*  the memory there is.
+*/
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/* A zip with nothing in it is just its end of central directory record */
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+
+/// Most a member can decompress to
+pub const MAX_MEMBER_LEN: u64 = 1024 * 1024 * 1024;
+
+/// Most all of an archive's members can decompress to together
+pub const MAX_TOTAL_LEN: u64 = 4 * MAX_MEMBER_LEN;
+
+
+/// Call `each` with the path in the archive and the contents of each of
+/// the regular files in the archive at `path`, in turn
+pub fn for_each_member<F: FnMut(String, Vec<u8>)>(path: &Path, each: F) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|err| err.to_string())?;
+    let mut magic = Vec::new();
+    (&mut file).take(4).read_to_end(&mut magic).map_err(|err| err.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|err| err.to_string())?;
+
+    if magic.starts_with(ZIP_MAGIC) || magic.starts_with(EMPTY_ZIP_MAGIC) {
+        zip_members(file, each)
+    }
+    else if magic.starts_with(GZIP_MAGIC) {
+        tar_members(GzDecoder::new(BufReader::new(file)), each)
+    }
+    else {
+        tar_members(BufReader::new(file), each)
+    }
+}
+
+
+fn tar_members<R: Read, F: FnMut(String, Vec<u8>)>(reader: R, mut each: F) -> Result<(), String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries().map_err(|err| format!("Not a tar archive ({})", err))?;
+
+    let mut left = MAX_TOTAL_LEN;
+    for entry in entries {
+        let mut entry = entry.map_err(|err| format!("Bad tar archive ({})", err))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = match entry.path() {
+            Ok(name) => name.to_string_lossy().into_owned(),
+            Err(err) => {
+                return Err(format!("Bad tar member name ({})", err));
+            },
+        };
+        let contents = read_member(&name, &mut entry, MAX_MEMBER_LEN, &mut left)?;
+        each(name, contents);
+    }
+    Ok(())
+}
+
+
+fn zip_members<F: FnMut(String, Vec<u8>)>(file: File, mut each: F) -> Result<(), String> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))
+        .map_err(|err| format!("Not a zip archive ({})", err))?;
+
+    let mut left = MAX_TOTAL_LEN;
+    for index in 0..archive.len() {
+        let mut member = archive.by_index(index).map_err(|err| format!("Bad zip archive ({})", err))?;
+        if !member.is_file() {
+            continue;
+        }
+        let name = member.name().to_owned();
+        let contents = read_member(&name, &mut member, MAX_MEMBER_LEN, &mut left)?;
+        each(name, contents);
+    }
+    Ok(())
+}
+
+
+/// The contents of member `name`, read from `reader`, if they're no more
+/// than `max_len` bytes or the `left` of the archive's total, which they're
+/// taken off
+fn read_member<R: Read>(name: &str, reader: R, max_len: u64, left: &mut u64) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    match reader.take(max_len.min(*left) + 1).read_to_end(&mut contents) {
+        Ok(len) if len as u64 > max_len => {
+            Err(format!("'{}' in archive is over {} bytes", name, max_len))
+        },
+        Ok(len) if len as u64 > *left => {
+            Err(format!("Archive's members come to over {} bytes", MAX_TOTAL_LEN))
+        },
+        Ok(len) => {
+            *left -= len as u64;
+            Ok(contents)
+        },
+        Err(err) => Err(format!("Unable to read '{}' from archive ({})", name, err)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-archive-{}-{}", name, std::process::id()));
+        path
+    }
+
+    fn members(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let mut members = Vec::new();
+        for_each_member(path, |name, contents| members.push((name, contents)))?;
+        Ok(members)
+    }
+
+    fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_size(0);
+        builder.append_data(&mut dir, "dir/", &[][..]).unwrap();
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_tar_members() {
+        let files: &[(&str, &[u8])] = &[("dir/a", b"known"), ("b", b"")];
+        let expected = vec![("dir/a".to_owned(), b"known".to_vec()), ("b".to_owned(), vec![])];
+
+        let path = temp_path("tar");
+        std::fs::write(&path, tar_of(files)).unwrap();
+        assert_eq!(members(&path), Ok(expected.clone()));
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar_of(files)).unwrap();
+        std::fs::write(&path, gz.finish().unwrap()).unwrap();
+        assert_eq!(members(&path), Ok(expected));
+
+        std::fs::write(&path, b"not an archive at all, really not").unwrap();
+        assert!(members(&path).is_err());
+        assert!(members(Path::new("/nonexistent")).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_zip_members() {
+        let path = temp_path("zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("dir/", options).unwrap();
+        writer.start_file("dir/a", options).unwrap();
+        writer.write_all(b"known").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(members(&path), Ok(vec![("dir/a".to_owned(), b"known".to_vec())]));
+
+        zip::ZipWriter::new(File::create(&path).unwrap()).finish().unwrap();
+        assert_eq!(members(&path), Ok(vec![]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_member_len_capped() {
+        let mut left = MAX_TOTAL_LEN;
+        assert_eq!(read_member("a", &b"known"[..], 5, &mut left), Ok(b"known".to_vec()));
+        assert_eq!(left, MAX_TOTAL_LEN - 5);
+        assert!(read_member("a", &b"known"[..], 4, &mut left).unwrap_err().contains("'a' in archive"));
+        assert!(read_member("a", std::io::repeat(0), 1024, &mut left).is_err());
+
+        /* Members that fit on their own but not all together */
+        let mut left = 8;
+        assert!(read_member("a", &b"known"[..], 5, &mut left).is_ok());
+        assert!(read_member("b", &b"known"[..], 5, &mut left).unwrap_err().contains("members come to"));
+    }
+}
@@ -0,0 +1,124 @@
+/* Content-defined chunking, for finding files that share most of their
+*  contents
+*
+*  Files are cut into chunks wherever a rolling "gear" hash of the last
+*  bytes seen matches a mask, as in FastCDC (Xia et al., 2016), so an edit
+*  only changes the chunks around it and the rest still match those of the
+*  original.  Chunks are at least MIN_SIZE and at most MAX_SIZE bytes, and
+*  normalized towards AVG_SIZE by using a harder to match mask before it and
+*  an easier one after.  Anything no bigger than MIN_SIZE is one chunk, so
+*  small files are the same item whether chunked or not.
+*/
+
+pub const MIN_SIZE: usize = 2 * 1024;
+pub const AVG_SIZE: usize = 8 * 1024;
+pub const MAX_SIZE: usize = 64 * 1024;
+
+/* log2(AVG_SIZE) plus and minus 2 bits, taken from the top of the hash
+ * since those depend on the most bytes */
+const MASK_HARD: u64 = !0 << (64 - 15);
+const MASK_EASY: u64 = !0 << (64 - 11);
+
+
+const GEAR: [u64; 256] = gear_table();
+
+
+/* Fixed pseudorandom values for each byte, from splitmix64, so chunk
+ * boundaries (and so filters) are the same everywhere */
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+
+/// Where the chunk at the start of `data` ends
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_SIZE);
+    let normal = AVG_SIZE.min(end);
+
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal {MASK_HARD} else {MASK_EASY};
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+
+/// `data` cut into chunks, each with its offset.  Empty data is one empty
+/// chunk.
+pub fn chunks(data: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = cut_point(&data[offset..]);
+        chunks.push((offset, &data[offset..offset + len]));
+        offset += len;
+        if offset == data.len() {
+            return chunks;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudorandom(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks(b""), vec![(0, &b""[..])]);
+        assert_eq!(chunks(b"small"), vec![(0, &b"small"[..])]);
+
+        let data = pseudorandom(1024 * 1024, 1);
+        let cut = chunks(&data);
+        assert_eq!(cut.iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect::<Vec<u8>>(), data);
+        assert!(cut.iter().all(|(_, chunk)| chunk.len() <= MAX_SIZE));
+        assert!(cut[..cut.len() - 1].iter().all(|(_, chunk)| chunk.len() > MIN_SIZE));
+        assert!(cut.windows(2).all(|pair| pair[0].0 + pair[0].1.len() == pair[1].0));
+        /* Roughly the average size, give or take */
+        assert!(cut.len() > 1024 / 16 && cut.len() < 1024 / 4);
+        assert_eq!(chunks(&data), cut);
+
+        /* Zeros never match a mask, so get cut at the maximum */
+        assert!(chunks(&[0; 3 * MAX_SIZE]).iter().all(|(_, chunk)| chunk.len() == MAX_SIZE));
+    }
+
+    #[test]
+    fn test_edits_keep_most_chunks() {
+        let data = pseudorandom(1024 * 1024, 2);
+        let mut edited = data.clone();
+        edited.splice(500_000..500_000, b"an insertion".iter().copied());
+        edited[100_000] ^= 0xff;
+
+        let original: Vec<&[u8]> = chunks(&data).into_iter().map(|(_, chunk)| chunk).collect();
+        let after: Vec<&[u8]> = chunks(&edited).into_iter().map(|(_, chunk)| chunk).collect();
+        let kept = after.iter().filter(|chunk| original.contains(chunk)).count();
+        assert!(kept + 6 >= after.len(), "only kept {} of {}", kept, after.len());
+        assert!(kept < after.len());
+    }
+}
@@ -0,0 +1,236 @@
+/* Finding duplicate files in a directory tree
+*
+*  Files are run through an in-memory filter one at a time, and any that's
+*  (probably) in it already when it's reached is a candidate duplicate of
+*  something before it.  That only needs the filter in memory, however many
+*  files there are, but some candidates will be false positives.  Confirming
+*  them compares each against the earlier files of the same size, which
+*  means remembering every file's path and size, though not its contents.
+*
+*  The filter gets BITS_PER_FILE bits for each file to be checked, which with
+*  NUM_HASHES hashes makes a little under 1% of new files candidates.
+*/
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::{num_u64s, read_item_hashes, test_and_insert_hashes};
+
+const BITS_PER_FILE: usize = 10;
+
+
+/// What a file turned out to be when it was reached
+#[derive(Debug, PartialEq)]
+pub enum Found {
+    /// Not seen before
+    New,
+    /// (Probably) seen before, unconfirmed
+    Candidate,
+    /// Confirmed the same as this earlier file
+    Duplicate(PathBuf),
+    /// A candidate that no earlier file actually matched
+    FalsePositive,
+}
+
+
+pub struct DupeFinder {
+    filter: Vec<u64>,
+    m: NonZeroUsize,
+    /// Files seen so far by size, if confirming candidates
+    seen: Option<HashMap<u64, Vec<PathBuf>>>,
+}
+
+
+impl DupeFinder {
+    /// A finder with a filter sized for checking `files` files
+    pub fn for_files(files: usize, confirm: bool) -> DupeFinder {
+        let m = NonZeroUsize::new(files.max(1).saturating_mul(BITS_PER_FILE)).unwrap_or(NonZeroUsize::MIN);
+        DupeFinder::new(m, confirm)
+    }
+
+
+    pub fn new(m: NonZeroUsize, confirm: bool) -> DupeFinder {
+        DupeFinder {
+            filter: vec![0; num_u64s(m)],
+            m,
+            seen: if confirm {Some(HashMap::new())} else {None},
+        }
+    }
+
+
+    /// Add the file at `path`, saying whether it's a duplicate.  It's
+    /// hashed as it's read, so is never in memory whole.
+    pub fn check(&mut self, path: &Path) -> Result<Found, String> {
+        let unreadable = |err: io::Error| format!("Unable to read '{}' ({})", path.display(), err);
+        let file = File::open(path).map_err(unreadable)?;
+        let len = file.metadata().map_err(unreadable)?.len();
+        let hashes = read_item_hashes(BufReader::new(file)).map_err(unreadable)?;
+        let was_in = test_and_insert_hashes(&hashes, &mut self.filter, self.m)?;
+        let seen = match self.seen.as_mut() {
+            Some(seen) => seen.entry(len).or_default(),
+            None => {
+                return Ok(if was_in {Found::Candidate} else {Found::New});
+            },
+        };
+
+        if was_in {
+            for earlier in seen.iter() {
+                let same = same_contents(earlier, path)
+                    .map_err(|err| format!("Unable to compare '{}' ({})", earlier.display(), err))?;
+                if same {
+                    return Ok(Found::Duplicate(earlier.clone()));
+                }
+            }
+        }
+        seen.push(path.to_owned());
+        Ok(if was_in {Found::FalsePositive} else {Found::New})
+    }
+}
+
+
+/// Do the files at `a` and `b` have exactly the same contents?
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut a_buffer = [0; 8192];
+    let mut b_buffer = [0; 8192];
+    loop {
+        let a_len = read_fully(&mut a, &mut a_buffer)?;
+        let b_len = read_fully(&mut b, &mut b_buffer)?;
+        if a_buffer[..a_len] != b_buffer[..b_len] {
+            return Ok(false);
+        }
+        if a_len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+
+/* Fill as much of `buffer` as there's left to read, so two files are read
+ * in step however their reads come back */
+fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(len)
+}
+
+
+/// Every regular file under `dir`, in a stable order.  Symlinks aren't
+/// followed so nothing's reached twice.  What can't be reached under `dir`
+/// is left out and said why in `skipped`.
+pub fn files_under(dir: &Path, skipped: &mut Vec<String>) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+        Err(err) => {
+            return Err(format!("Unable to list '{}' ({})", dir.display(), err));
+        },
+    };
+    entries.sort();
+
+    for path in entries {
+        let file_type = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(err) => {
+                skipped.push(format!("Cannot access '{}' ({})", path.display(), err));
+                continue;
+            },
+        };
+        if file_type.is_dir() {
+            match files_under(&path, skipped) {
+                Ok(under) => files.extend(under),
+                Err(err) => skipped.push(err),
+            }
+        }
+        else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_tree() -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bloom-cli-dupes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        fs::write(dir.join("a"), b"known").unwrap();
+        fs::write(dir.join("b"), b"other").unwrap();
+        fs::write(dir.join("sub/deeper/c"), b"known").unwrap();
+        fs::write(dir.join("sub/d"), b"known!").unwrap();
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("link")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_find_dupes() {
+        let dir = temp_tree();
+        let mut skipped = Vec::new();
+        let files = files_under(&dir, &mut skipped).unwrap();
+        assert_eq!(files, vec![dir.join("a"), dir.join("b"), dir.join("sub/d"), dir.join("sub/deeper/c")]);
+        assert!(skipped.is_empty());
+
+        let m = NonZeroUsize::new(100_000).unwrap();
+        let mut unconfirmed = DupeFinder::new(m, false);
+        let found: Vec<Found> = files.iter().map(|file| unconfirmed.check(file).unwrap()).collect();
+        assert_eq!(found, vec![Found::New, Found::New, Found::New, Found::Candidate]);
+
+        let mut confirmed = DupeFinder::new(m, true);
+        let found: Vec<Found> = files.iter().map(|file| confirmed.check(file).unwrap()).collect();
+        assert_eq!(found[3], Found::Duplicate(dir.join("a")));
+
+        /* A filter so small everything looks seen */
+        let mut tiny = DupeFinder::new(NonZeroUsize::new(1).unwrap(), true);
+        let found: Vec<Found> = files.iter().map(|file| tiny.check(file).unwrap()).collect();
+        assert_eq!(found[1], Found::FalsePositive);
+        assert_eq!(found[2], Found::FalsePositive);
+        assert_eq!(found[3], Found::Duplicate(dir.join("a")));
+
+        /* Sized to the files, and still finding them */
+        let mut sized = DupeFinder::for_files(files.len(), false);
+        assert_eq!(sized.filter.len(), 1);
+        let found: Vec<Found> = files.iter().map(|file| sized.check(file).unwrap()).collect();
+        assert_eq!(found[3], Found::Candidate);
+        assert_eq!(DupeFinder::for_files(0, false).m.get(), BITS_PER_FILE);
+        assert_eq!(DupeFinder::for_files(1_000_000, false).m.get(), 10_000_000);
+
+        assert!(sized.check(&dir.join("nonexistent")).is_err());
+        assert!(files_under(&dir.join("nonexistent"), &mut skipped).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_contents() {
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("bloom-cli-same-a-{}", std::process::id()));
+        let b = dir.join(format!("bloom-cli-same-b-{}", std::process::id()));
+        let big: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        fs::write(&a, &big).unwrap();
+        fs::write(&b, &big).unwrap();
+        assert!(same_contents(&a, &b).unwrap());
+        fs::write(&b, &big[..big.len() - 1]).unwrap();
+        assert!(!same_contents(&a, &b).unwrap());
+        fs::write(&a, b"").unwrap();
+        fs::write(&b, b"").unwrap();
+        assert!(same_contents(&a, &b).unwrap());
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+    }
+}
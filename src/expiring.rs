@@ -0,0 +1,224 @@
+/* Expiring filters, for items that must be forgotten after a while
+*
+*  Where a plain filter has bits, an expiring one has cells holding when
+*  they expire, in seconds since the filter's epoch (when it was made), 0
+*  for never set.  Each insert gives its items a TTL, the filter's `ttl`
+*  unless it says otherwise, and pushes their cells' deadlines out to that
+*  long from now, never bringing them in.  An item's in the filter until
+*  any of its cells expires, so it's forgotten its TTL after it was last
+*  inserted, or later if items kept longer share all its cells.
+*
+*  Queries ignore expired cells without writing anything, so expired items
+*  are absent as soon as they expire.  `bloom-cli compact` clears them on
+*  disk too, e.g. from cron, for when they mustn't be kept at all.
+*
+*  Cells are 32 bits, two to a u64, the first in the top half, which lasts
+*  136 years from the epoch.
+*/
+use std::fmt;
+use std::num::NonZeroUsize;
+
+use crate::ItemHashes;
+
+const CELL_BITS: usize = 32;
+const CELLS_PER_U64: usize = 64 / CELL_BITS;
+
+
+/// How long an expiring filter keeps items by default, counting from when
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    /// Seconds items are kept after they're inserted, unless an insert says
+    /// otherwise
+    pub ttl: u64,
+    /// What cells count from, in seconds since the Unix epoch
+    pub epoch: u64,
+}
+
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expiring items after {}s by default", self.ttl)
+    }
+}
+
+
+impl Expiry {
+    pub fn new(ttl: u64, epoch: u64) -> Result<Expiry, String> {
+        check_ttl(ttl)?;
+        Ok(Expiry {ttl, epoch})
+    }
+
+
+    /// How many u64s the cells of a filter with `m` of them take up
+    pub fn body_u64s(m: NonZeroUsize) -> usize {
+        (usize::from(m) - 1) / CELLS_PER_U64 + 1
+    }
+
+
+    /* `now` as cells have it */
+    fn since_epoch(&self, now: u64) -> u32 {
+        u32::try_from(now.saturating_sub(self.epoch)).unwrap_or(u32::MAX)
+    }
+
+
+    /// Is an item with `hashes` (probably) in `filter` and not yet expired
+    /// at `now`?
+    pub fn contains(&self, hashes: &ItemHashes, filter: &[u64], m: NonZeroUsize, now: u64) -> Result<bool, String> {
+        let now = self.since_epoch(now);
+        for hash in hashes.iter() {
+            if cell(filter, *hash as usize % usize::from(m))? <= now {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+
+    /// Insert an item with `hashes` into `filter` at `now`, to expire `ttl`
+    /// seconds later unless its cells were already to expire later still,
+    /// returning whether it was (probably) in already.
+    pub fn test_and_insert(
+        &self,
+        hashes: &ItemHashes,
+        filter: &mut [u64],
+        m: NonZeroUsize,
+        now: u64,
+        ttl: u64
+    ) -> Result<bool, String> {
+        check_ttl(ttl)?;
+        let was_in = self.contains(hashes, filter, m, now)?;
+        let deadline = self.since_epoch(now).saturating_add(ttl as u32);
+        for hash in hashes.iter() {
+            let index = *hash as usize % usize::from(m);
+            if cell(filter, index)? < deadline {
+                set_cell(filter, index, deadline)?;
+            }
+        }
+        Ok(was_in)
+    }
+
+
+    /// Clear every cell in `filter` that's expired at `now`, returning how
+    /// many were
+    pub fn compact(&self, filter: &mut [u64], m: NonZeroUsize, now: u64) -> Result<usize, String> {
+        let now = self.since_epoch(now);
+        let mut cleared = 0;
+        for index in 0..usize::from(m) {
+            let deadline = cell(filter, index)?;
+            if deadline != 0 && deadline <= now {
+                set_cell(filter, index, 0)?;
+                cleared += 1;
+            }
+        }
+        Ok(cleared)
+    }
+}
+
+
+/// Is `ttl` one cells can hold?
+pub fn check_ttl(ttl: u64) -> Result<(), String> {
+    if ttl == 0 || ttl > u32::MAX as u64 {
+        return Err(format!("A TTL must be 1 to {} seconds", u32::MAX));
+    }
+    Ok(())
+}
+
+
+fn cell(filter: &[u64], index: usize) -> Result<u32, String> {
+    match filter.get(index / CELLS_PER_U64) {
+        Some(int) => Ok((int >> shift(index)) as u32),
+        None => Err(format!("Filter has {} u64s, too few for cell {}", filter.len(), index)),
+    }
+}
+
+
+fn set_cell(filter: &mut [u64], index: usize, deadline: u32) -> Result<(), String> {
+    let len = filter.len();
+    match filter.get_mut(index / CELLS_PER_U64) {
+        Some(int) => {
+            *int = (*int & !(0xffffffff << shift(index))) | ((deadline as u64) << shift(index));
+            Ok(())
+        },
+        None => Err(format!("Filter has {} u64s, too few for cell {}", len, index)),
+    }
+}
+
+
+/* Where cell `index` is in its u64 */
+fn shift(index: usize) -> usize {
+    64 - CELL_BITS * (index % CELLS_PER_U64 + 1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+
+    #[test]
+    fn test_cells() {
+        let mut filter = vec![0; 2];
+        assert!(set_cell(&mut filter, 0, 7).is_ok());
+        assert!(set_cell(&mut filter, 3, u32::MAX).is_ok());
+        assert_eq!(filter, vec![0x0000000700000000, 0x00000000ffffffff]);
+        assert_eq!(cell(&filter, 0), Ok(7));
+        assert_eq!(cell(&filter, 1), Ok(0));
+        assert_eq!(cell(&filter, 3), Ok(u32::MAX));
+        assert!(cell(&filter, 4).is_err());
+        assert!(set_cell(&mut filter, 4, 1).is_err());
+        assert_eq!(Expiry::body_u64s(NonZeroUsize::new(3).unwrap()), 2);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let expiry = Expiry::new(60, 1_000_000).unwrap();
+        let mut filter = vec![0; Expiry::body_u64s(m)];
+        let known = item_hashes(b"known");
+        let other = item_hashes(b"other");
+
+        assert_eq!(expiry.test_and_insert(&known, &mut filter, m, 1_000_010, 60), Ok(false));
+        assert_eq!(expiry.test_and_insert(&known, &mut filter, m, 1_000_020, 60), Ok(true));
+        assert_eq!(expiry.test_and_insert(&other, &mut filter, m, 1_000_050, 60), Ok(false));
+        assert_eq!(expiry.contains(&known, &filter, m, 1_000_079), Ok(true));
+        /* 60s after it was last inserted */
+        assert_eq!(expiry.contains(&known, &filter, m, 1_000_080), Ok(false));
+        assert_eq!(expiry.contains(&other, &filter, m, 1_000_080), Ok(true));
+
+        /* Compacting clears only what's expired */
+        let set = |filter: &[u64]| (0..1000).filter(|i| cell(filter, *i).unwrap() != 0).count();
+        let before = set(&filter);
+        assert_eq!(expiry.compact(&mut filter, m, 1_000_080), Ok(before - set(&filter)));
+        assert!(set(&filter) > 0);
+        assert_eq!(expiry.contains(&other, &filter, m, 1_000_080), Ok(true));
+        assert!(expiry.compact(&mut filter, m, 1_000_110).is_ok());
+        assert!(filter.iter().all(|int| *int == 0));
+    }
+
+    #[test]
+    fn test_ttl_per_insert() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let expiry = Expiry::new(60, 0).unwrap();
+        let mut filter = vec![0; Expiry::body_u64s(m)];
+        let short = item_hashes(b"short");
+        let long = item_hashes(b"long");
+
+        assert_eq!(expiry.test_and_insert(&short, &mut filter, m, 100, 10), Ok(false));
+        assert_eq!(expiry.test_and_insert(&long, &mut filter, m, 100, 3600), Ok(false));
+        assert_eq!(expiry.contains(&short, &filter, m, 110), Ok(false));
+        assert_eq!(expiry.contains(&long, &filter, m, 3699), Ok(true));
+
+        /* A shorter TTL later doesn't bring a deadline in */
+        assert_eq!(expiry.test_and_insert(&long, &mut filter, m, 200, 10), Ok(true));
+        assert_eq!(expiry.contains(&long, &filter, m, 3699), Ok(true));
+        assert_eq!(expiry.contains(&long, &filter, m, 3700), Ok(false));
+        assert!(expiry.test_and_insert(&long, &mut filter, m, 200, 0).is_err());
+    }
+
+    #[test]
+    fn test_ttl_checked() {
+        assert!(Expiry::new(0, 0).is_err());
+        assert!(Expiry::new(1 << 40, 0).is_err());
+        assert_eq!(Expiry::new(86400, 0).unwrap().to_string(), "expiring items after 86400s by default");
+    }
+}
@@ -0,0 +1,679 @@
+/* On-disk layout of a filter file
+*
+*  Everything is big-endian.
+*
+*   offset  size  field
+*        0     8  magic number "BLOOMCLI"
+*        8     4  format version
+*       12     4  k, number of hashes per item
+*       16     8  m, number of bits in the filter
+*       24     8  xxh3 checksum (see below)
+*       32   ...  the bit array as u64s
+*
+*  Filters made with normalizers (see normalize.rs) are version 2, whose
+*  header is longer:
+*
+*       32     4  normalizers applied to items before they're hashed
+*       36     4  reserved, 0
+*       40   ...  the bit array as u64s
+*
+*  Filters without them are still written as version 1, so older versions
+*  of bloom-cli can read them.
+*
+*  Filters of a kind other than a plain bit array (see kind.rs) are version 3,
+*  whose header is longer again:
+*
+*       32     4  normalizers
+*       36     4  kind
+*       40    24  three u64 fields whose meaning depends on the kind
+*       64   ...  the filter's u64s, as many as the kind needs
+*
+*  A version 1 checksum is of the bit array alone, as older versions of
+*  bloom-cli expect.  Later versions' checksums are of the whole header but
+*  the checksum itself (bytes 0-23 and 32 on) followed by the filter's u64s,
+*  so a flipped normalizer or kind field is caught as well.
+*
+*  Files written before the header existed are just the bit array and are
+*  still readable (without any checksum to check).  They're upgraded the
+*  next time they're written.
+*/
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::process;
+use xxhash_rust::xxh3;
+
+use crate::kind::Kind;
+use crate::normalize::Normalize;
+use crate::{M_NZ, NUM_HASHES};
+
+pub const MAGIC: &[u8; 8] = b"BLOOMCLI";
+pub const VERSION: u32 = 1;
+pub const NORMALIZED_VERSION: u32 = 2;
+pub const KIND_VERSION: u32 = 3;
+/// Length of a version 1 header, the shortest there is
+pub const HEADER_LEN: usize = 32;
+pub const NORMALIZED_HEADER_LEN: usize = 40;
+pub const KIND_HEADER_LEN: usize = 64;
+pub const MAX_HEADER_LEN: usize = KIND_HEADER_LEN;
+
+
+/// What the header says about the bit array following it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Header {
+    /// 0 for legacy files with no header at all
+    pub version: u32,
+    pub num_hashes: u32,
+    pub m: NonZeroUsize,
+    /// Legacy files have no checksum
+    pub checksum: Option<u64>,
+    pub normalize: Normalize,
+    pub kind: Kind,
+}
+
+
+impl Header {
+    pub fn new(m: NonZeroUsize, normalize: Normalize, kind: Kind, filter: &[u64]) -> Header {
+        let version = if kind != Kind::Plain {
+            KIND_VERSION
+        }
+        else if !normalize.is_none() {
+            NORMALIZED_VERSION
+        }
+        else {
+            VERSION
+        };
+        let mut header = Header {version, num_hashes: NUM_HASHES, m, checksum: None, normalize, kind};
+        let mut hasher = header.hasher();
+        for int in filter.iter() {
+            hasher.update(&int.to_be_bytes());
+        }
+        header.checksum = Some(hasher.digest());
+        header
+    }
+
+
+    /// The checksum a filter with this header and raw bit array `body`
+    /// should have
+    pub fn checksum_of(&self, body: &[u8]) -> u64 {
+        let mut hasher = self.hasher();
+        hasher.update(body);
+        hasher.digest()
+    }
+
+
+    /* A hasher that's already had whatever of the header the checksum
+     * covers */
+    fn hasher(&self) -> xxh3::Xxh3 {
+        let mut hasher = xxh3::Xxh3::new();
+        if self.version >= NORMALIZED_VERSION {
+            let bytes = self.to_bytes();
+            hasher.update(&bytes[..24]);
+            hasher.update(&bytes[32..]);
+        }
+        hasher
+    }
+
+
+    /// How many bytes of the file the header takes up
+    pub fn byte_len(&self) -> usize {
+        header_len(self.version)
+    }
+
+
+    /// How many u64s follow the header
+    pub fn body_u64s(&self) -> Result<usize, String> {
+        self.kind.body_u64s(self.m)
+    }
+
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![0; self.byte_len()];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.num_hashes.to_be_bytes());
+        bytes[16..24].copy_from_slice(&(usize::from(self.m) as u64).to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.checksum.unwrap_or(0).to_be_bytes());
+        if self.version >= NORMALIZED_VERSION {
+            bytes[32..36].copy_from_slice(&self.normalize.flags().to_be_bytes());
+        }
+        if self.version >= KIND_VERSION {
+            let (number, fields) = self.kind.to_fields();
+            bytes[36..40].copy_from_slice(&number.to_be_bytes());
+            for (i, field) in fields.iter().enumerate() {
+                bytes[40 + i * 8..48 + i * 8].copy_from_slice(&field.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+
+    /// Parse the header at the start of `bytes` (which must start with
+    /// `MAGIC`)
+    fn from_bytes(bytes: &[u8]) -> Result<Header, String> {
+        if bytes.len() < HEADER_LEN {
+            return Err(format!(
+                "Header is truncated ({} of {} bytes)",
+                bytes.len(),
+                HEADER_LEN
+            ));
+        }
+        let be_u32 = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let be_u64 = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+
+        let version = be_u32(8);
+        if version != VERSION && version != NORMALIZED_VERSION && version != KIND_VERSION {
+            return Err(format!("Unsupported filter format version {}", version));
+        }
+        if bytes.len() < header_len(version) {
+            return Err(format!(
+                "Header is truncated ({} of {} bytes)",
+                bytes.len(),
+                header_len(version)
+            ));
+        }
+        let num_hashes = be_u32(12);
+        if num_hashes != NUM_HASHES {
+            return Err(format!(
+                "Filter uses {} hashes per item, only {} is supported",
+                num_hashes,
+                NUM_HASHES
+            ));
+        }
+        let m = match NonZeroUsize::new(be_u64(16) as usize) {
+            Some(m) => m,
+            None => {
+                return Err("Header says the filter has 0 bits".to_owned());
+            },
+        };
+
+        let normalize = if version >= NORMALIZED_VERSION {
+            Normalize::from_flags(be_u32(32))?
+        }
+        else {
+            Normalize::NONE
+        };
+        let kind = if version >= KIND_VERSION {
+            Kind::from_fields(be_u32(36), [be_u64(40), be_u64(48), be_u64(56)], m)?
+        }
+        else {
+            Kind::Plain
+        };
+
+        Ok(Header {version, num_hashes, m, checksum: Some(be_u64(24)), normalize, kind})
+    }
+}
+
+
+/// How many bytes a header of format `version` takes up
+fn header_len(version: u32) -> usize {
+    match version {
+        0 => 0,
+        VERSION => HEADER_LEN,
+        NORMALIZED_VERSION => NORMALIZED_HEADER_LEN,
+        _ => KIND_HEADER_LEN,
+    }
+}
+
+
+/// Checksum of the bit array as it's laid out on disk, which is all a
+/// version 1 header's covers
+pub fn checksum(filter: &[u64]) -> u64 {
+    let mut hasher = xxh3::Xxh3::new();
+    for int in filter.iter() {
+        hasher.update(&int.to_be_bytes());
+    }
+    hasher.digest()
+}
+
+
+fn words_from_bytes(bytes: &[u8]) -> Vec<u64> {
+    bytes.chunks_exact(8)
+        .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+
+/// Is the bit array exactly as long as `header` says it should be?
+fn check_length(header: &Header, body_len: usize) -> Result<(), String> {
+    /* Every kind takes at least a bit per bit of m, so a bigger m can't be
+     * right, and checking first keeps what follows from overflowing */
+    if body_len.checked_mul(8).is_some_and(|bits| usize::from(header.m) > bits) {
+        return Err(format!("Bit array is {} bytes, too short for m = {} (truncated?)", body_len, header.m));
+    }
+    let expected_len = header.body_u64s()?.checked_mul(8).ok_or_else(|| {
+        format!("m = {} {} is too big", header.m, header.kind)
+    })?;
+    if body_len != expected_len {
+        return Err(format!(
+            "Bit array is {} bytes but m = {}{} needs exactly {} bytes{}",
+            body_len,
+            header.m,
+            if header.kind == Kind::Plain {String::new()} else {format!(" {}", header.kind)},
+            expected_len,
+            if body_len < expected_len {" (truncated?)"} else {""}
+        ));
+    }
+    Ok(())
+}
+
+
+/// Parse the header from the first bytes of a filter file that's
+/// `total_len` bytes long, checking the bit array is exactly as long as m
+/// requires.  `prefix` must hold at least the first `MAX_HEADER_LEN` bytes (or
+/// the whole file if it's shorter).  Returns the header and the offset of
+/// the bit array.
+pub fn parse_header(prefix: &[u8], total_len: usize) -> Result<(Header, usize), String> {
+    if !prefix.starts_with(MAGIC) {
+        let legacy = Header {
+            version: 0,
+            num_hashes: NUM_HASHES,
+            m: M_NZ,
+            checksum: None,
+            normalize: Normalize::NONE,
+            kind: Kind::Plain,
+        };
+        check_length(&legacy, total_len)?;
+        return Ok((legacy, 0));
+    }
+
+    let header = Header::from_bytes(prefix)?;
+    check_length(&header, total_len - header.byte_len())?;
+    Ok((header, header.byte_len()))
+}
+
+
+/// Split a whole filter file into its header and the raw bytes of its bit
+/// array, checking that the bit array is exactly as long as m requires.
+/// Doesn't look at the bits themselves, so the checksum isn't checked.
+pub fn split_filter(bytes: &[u8]) -> Result<(Header, &[u8]), String> {
+    let (header, body_offset) = parse_header(bytes, bytes.len())?;
+    Ok((header, &bytes[body_offset..]))
+}
+
+
+/// Do `header` and the raw bit array `body` match the checksum in `header`
+/// (if any)?
+pub fn check_checksum(header: &Header, body: &[u8]) -> Result<(), String> {
+    if let Some(expected) = header.checksum {
+        let actual = header.checksum_of(body);
+        if actual != expected {
+            return Err(format!(
+                "Checksum mismatch (header says {:016x}, filter hashes to {:016x})",
+                expected,
+                actual
+            ));
+        }
+    }
+    Ok(())
+}
+
+
+/// Split a whole filter file into its header and bit array, checking that
+/// the bit array is exactly as long as m requires and matches the checksum
+/// if there is one.
+pub fn parse_filter(bytes: &[u8]) -> Result<(Header, Vec<u64>), String> {
+    let (header, body) = split_filter(bytes)?;
+    check_checksum(&header, body)?;
+    Ok((header, words_from_bytes(body)))
+}
+
+
+/// The u64 at `index` in a raw bit array
+pub fn word_at(body: &[u8], index: usize) -> Result<u64, String> {
+    match body.get(index * 8..index * 8 + 8) {
+        Some(word) => Ok(u64::from_be_bytes(word.try_into().unwrap())),
+        None => Err(format!("No u64 at index {} in the bit array", index)),
+    }
+}
+
+
+/// Overwrite the u64 at `index` in a raw bit array
+pub fn set_word_at(body: &mut [u8], index: usize, word: u64) -> Result<(), String> {
+    match body.get_mut(index * 8..index * 8 + 8) {
+        Some(slot) => {
+            slot.copy_from_slice(&word.to_be_bytes());
+            Ok(())
+        },
+        None => Err(format!("No u64 at index {} in the bit array", index)),
+    }
+}
+
+
+/// Recompute the checksum of a whole filter file and store it in its
+/// header, for after bits have been changed in place.
+pub fn update_checksum(bytes: &mut [u8]) -> Result<(), String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("Legacy filters have no header to hold a checksum".to_owned());
+    }
+    let (header, body_offset) = parse_header(bytes, bytes.len())?;
+    let checksum = header.checksum_of(&bytes[body_offset..]);
+    bytes[24..32].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+
+/// Check everything about a filter file without keeping the bits.
+/// Returns the parsed header.
+pub fn verify_filter(bytes: &[u8]) -> Result<Header, String> {
+    parse_filter(bytes).map(|(header, _)| header)
+}
+
+
+/// Read a filter from anything readable, e.g. an already locked file.
+pub fn read_filter<R: Read>(mut reader: R) -> Result<(Header, Vec<u64>), String> {
+    let mut bytes = Vec::<u8>::new();
+    if let Err(err) = reader.read_to_end(&mut bytes) {
+        return Err(format!("{}", err));
+    }
+    parse_filter(&bytes)
+}
+
+
+/// Write a filter, header first, to anything writable, e.g. an already
+/// locked file.
+pub fn write_filter<W: Write>(writer: W, m: NonZeroUsize, filter: &[u64]) -> Result<(), String> {
+    write_normalized_filter(writer, m, Normalize::NONE, filter)
+}
+
+
+/// Write a filter whose items are normalized with `normalize`
+pub fn write_normalized_filter<W: Write>(
+    writer: W,
+    m: NonZeroUsize,
+    normalize: Normalize,
+    filter: &[u64]
+) -> Result<(), String> {
+    write_kind_filter(writer, m, normalize, Kind::Plain, filter)
+}
+
+
+/// Write a filter of any kind, whose `filter` must be as long as the kind
+/// needs
+pub fn write_kind_filter<W: Write>(
+    writer: W,
+    m: NonZeroUsize,
+    normalize: Normalize,
+    kind: Kind,
+    filter: &[u64]
+) -> Result<(), String> {
+    let mut writer = BufWriter::new(writer);
+    if let Err(err) = writer.write_all(&Header::new(m, normalize, kind, filter).to_bytes()) {
+        return Err(err.to_string());
+    }
+    for int in filter.iter() {
+        if let Err(err) = writer.write_all(&int.to_be_bytes()) {
+            return Err(err.to_string());
+        }
+    }
+    writer.flush().map_err(|err| err.to_string())
+}
+
+
+/// Replace the filter at `path`, which must be locked (see lock.rs), with
+/// `filter`, keeping the m, normalizers and kind in `header`.  The new one's
+/// written and synced beside it and renamed into place, so a crash leaves
+/// either the old filter or the new one whole.
+pub fn replace_filter(path: &Path, header: &Header, filter: &[u64]) -> Result<(), String> {
+    let path = fs::canonicalize(path).map_err(|err| err.to_string())?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        process::id()
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .and_then(|file| {
+            file.set_permissions(fs::metadata(&path)?.permissions())?;
+            Ok(file)
+        })
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            write_kind_filter(&file, header.m, header.normalize, header.kind, filter)?;
+            file.sync_all().map_err(|err| err.to_string())
+        })
+        .and_then(|()| fs::rename(&temp_path, &path).map_err(|err| err.to_string()));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written?;
+
+    /* The rename itself only lasts once the directory's synced */
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    File::open(dir).and_then(|dir| dir.sync_all()).map_err(|err| err.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::num_u64s;
+
+    #[test]
+    fn test_read_write_filter() {
+        let filter = vec![0xdeadbeef00000000, 0, 1, u64::MAX];
+        let m = NonZeroUsize::new(256).unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_filter(&mut on_disk, m, &filter).is_ok());
+        assert_eq!(on_disk.len(), HEADER_LEN + 32);
+        assert_eq!(&on_disk[..8], MAGIC);
+        assert_eq!(&on_disk[HEADER_LEN..HEADER_LEN + 8], &[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0]);
+
+        let (header, read_back) = read_filter(&on_disk[..]).unwrap();
+        assert_eq!(read_back, filter);
+        assert_eq!(header.m, m);
+        assert_eq!(header.checksum, Some(checksum(&filter)));
+        assert_eq!(verify_filter(&on_disk), Ok(header));
+    }
+
+    #[test]
+    fn test_normalized_filter() {
+        let filter = vec![0, 0xff];
+        let m = NonZeroUsize::new(128).unwrap();
+        let normalize: Normalize = "case,line-endings".parse().unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_normalized_filter(&mut on_disk, m, normalize, &filter).is_ok());
+        assert_eq!(on_disk.len(), NORMALIZED_HEADER_LEN + 16);
+
+        let (header, read_back) = parse_filter(&on_disk).unwrap();
+        assert_eq!(read_back, filter);
+        assert_eq!(header.version, NORMALIZED_VERSION);
+        assert_eq!(header.normalize, normalize);
+        assert_eq!(header.byte_len(), NORMALIZED_HEADER_LEN);
+
+        /* Changed in place, the checksum is recomputed to match */
+        on_disk[NORMALIZED_HEADER_LEN] = 1;
+        assert!(parse_filter(&on_disk).is_err());
+        assert!(update_checksum(&mut on_disk).is_ok());
+        assert_eq!(parse_filter(&on_disk).unwrap().1, vec![1 << 56, 0xff]);
+
+        /* Unknown normalizers and a header cut short */
+        assert!(parse_header(&on_disk[..NORMALIZED_HEADER_LEN - 1], on_disk.len()).is_err());
+        on_disk[32] = 0x80;
+        assert!(parse_filter(&on_disk).is_err());
+
+        /* Filters without normalizers stay version 1 */
+        let mut plain = Vec::<u8>::new();
+        assert!(write_filter(&mut plain, m, &filter).is_ok());
+        assert_eq!(parse_filter(&plain).unwrap().0.version, VERSION);
+        assert_eq!(parse_filter(&plain).unwrap().0.normalize, Normalize::NONE);
+    }
+
+    #[test]
+    fn test_kind_filter() {
+        let m = NonZeroUsize::new(128).unwrap();
+        let kind = Kind::Rotating(crate::rotating::Rotation::new(3, 60, 1_000_000).unwrap());
+        let filter = vec![1, 2, 3, 4, 5, 6];
+        let normalize: Normalize = "case".parse().unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_kind_filter(&mut on_disk, m, normalize, kind, &filter).is_ok());
+        assert_eq!(on_disk.len(), KIND_HEADER_LEN + 48);
+
+        let (header, read_back) = parse_filter(&on_disk).unwrap();
+        assert_eq!(read_back, filter);
+        assert_eq!(header.version, KIND_VERSION);
+        assert_eq!(header.kind, kind);
+        assert_eq!(header.normalize, normalize);
+        assert_eq!(header.body_u64s(), Ok(6));
+
+        /* One generation's worth short */
+        assert!(parse_filter(&on_disk[..on_disk.len() - 16]).unwrap_err().contains("rotating 3 generations"));
+        assert!(parse_header(&on_disk[..KIND_HEADER_LEN - 1], on_disk.len()).is_err());
+        on_disk[39] = 99;
+        assert!(parse_filter(&on_disk).is_err());
+
+        /* An m and generations that would overflow working out the length */
+        on_disk[39] = 1;
+        on_disk[16..24].copy_from_slice(&(1u64 << 63).to_be_bytes());
+        on_disk[40..48].copy_from_slice(&1024u64.to_be_bytes());
+        assert!(parse_filter(&on_disk).unwrap_err().contains("too short"));
+    }
+
+    #[test]
+    fn test_legacy_filter() {
+        let legacy = vec![0; num_u64s(M_NZ) * 8];
+        let (header, filter) = parse_filter(&legacy).unwrap();
+        assert_eq!(header.version, 0);
+        assert_eq!(header.checksum, None);
+        assert_eq!(header.m, M_NZ);
+        assert_eq!(filter.len(), num_u64s(M_NZ));
+        assert!(verify_filter(&legacy).is_ok());
+    }
+
+    #[test]
+    fn test_wrong_lengths() {
+        let legacy = vec![0; num_u64s(M_NZ) * 8];
+        for len in [0, 7, 8, legacy.len() - 8, legacy.len() - 1] {
+            assert!(parse_filter(&legacy[..len]).is_err());
+        }
+        let mut longer = legacy.clone();
+        longer.push(0);
+        assert!(parse_filter(&longer).is_err());
+
+        let m = NonZeroUsize::new(100).unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_filter(&mut on_disk, m, &[0, 0]).is_ok());
+        assert!(parse_filter(&on_disk).is_ok());
+        for len in HEADER_LEN..on_disk.len() {
+            let err = parse_filter(&on_disk[..len]).unwrap_err();
+            assert!(err.contains("truncated"));
+        }
+
+        /* Changing bits in place and then fixing up the checksum */
+        let (header, body) = split_filter(&on_disk).unwrap();
+        assert_eq!(word_at(body, 1), Ok(0));
+        assert!(word_at(body, 2).is_err());
+        let body_start = on_disk.len() - body.len();
+        assert!(set_word_at(&mut on_disk[body_start..], 1, 0xff).is_ok());
+        assert!(check_checksum(&header, &on_disk[body_start..]).is_err());
+        assert!(update_checksum(&mut on_disk).is_ok());
+        assert_eq!(parse_filter(&on_disk).unwrap().1, vec![0, 0xff]);
+
+        /* Header and bits disagree about m */
+        on_disk[23] = 200;
+        assert!(parse_filter(&on_disk).is_err());
+    }
+
+    /// Hands out at most 3 bytes per read() like a slow pipe might
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let filter = vec![1, 2, 3];
+        let m = NonZeroUsize::new(150).unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_filter(&mut on_disk, m, &filter).is_ok());
+        let (_, read_back) = read_filter(Trickle(&on_disk)).unwrap();
+        assert_eq!(read_back, filter);
+    }
+
+    #[test]
+    fn test_replace_filter() {
+        use std::os::unix::fs::PermissionsExt;
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bloom-cli-replace-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("filter");
+        let m = NonZeroUsize::new(128).unwrap();
+        write_filter(File::create(&path).unwrap(), m, &[1, 2]).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let mut old = File::open(&path).unwrap();
+
+        let (header, _) = read_filter(&old).unwrap();
+        assert!(replace_filter(&path, &header, &[3, 4]).is_ok());
+        assert_eq!(read_filter(File::open(&path).unwrap()).unwrap().1, vec![3, 4]);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        /* Whoever had the old one open still has it whole */
+        std::io::Seek::rewind(&mut old).unwrap();
+        assert_eq!(read_filter(&old).unwrap().1, vec![1, 2]);
+        /* No temporary file left beside it */
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corruption_detected() {
+        let filter = vec![0x0123456789abcdef, 0xfedcba9876543210];
+        let m = NonZeroUsize::new(128).unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_filter(&mut on_disk, m, &filter).is_ok());
+
+        /* Flip every bit of the bit array in turn */
+        for byte in HEADER_LEN..on_disk.len() {
+            for bit in 0..8 {
+                let mut flipped = on_disk.clone();
+                flipped[byte] ^= 1 << bit;
+                assert!(parse_filter(&flipped).is_err());
+                assert!(verify_filter(&flipped).is_err());
+            }
+        }
+
+        /* Extra words on the end */
+        let mut too_long = on_disk.clone();
+        too_long.extend_from_slice(&[0; 8]);
+        assert!(verify_filter(&too_long).is_err());
+
+        /* Unknown versions and truncated headers */
+        let mut bad_version = on_disk.clone();
+        bad_version[11] = 99;
+        assert!(parse_filter(&bad_version).is_err());
+        assert!(parse_filter(&on_disk[..HEADER_LEN - 1]).is_err());
+
+        /* A different but valid normalizer or rotation interval is caught too */
+        let kind = Kind::Rotating(crate::rotating::Rotation::new(2, 60, 1_000_000).unwrap());
+        let normalize: Normalize = "case".parse().unwrap();
+        let mut on_disk = Vec::<u8>::new();
+        assert!(write_kind_filter(&mut on_disk, m, normalize, kind, &[0; 4]).is_ok());
+        assert!(verify_filter(&on_disk).is_ok());
+        for (byte, bit) in [(35, 1), (35, 2), (55, 1)] {
+            let mut flipped = on_disk.clone();
+            flipped[byte] ^= bit;
+            assert!(parse_header(&flipped, flipped.len()).is_ok());
+            assert!(verify_filter(&flipped).unwrap_err().contains("Checksum mismatch"));
+        }
+
+        /* But a version 1 checksum is still of the bit array alone */
+        assert_ne!(on_disk[24..32], checksum(&[0; 4]).to_be_bytes());
+        let mut legacy = Vec::<u8>::new();
+        assert!(write_filter(&mut legacy, m, &filter).is_ok());
+        assert_eq!(legacy[24..32], checksum(&filter).to_be_bytes());
+    }
+}
@@ -0,0 +1,131 @@
+/* Blobs in a git repository's history as items
+*
+*  Every version of every file a repository has ever held is a blob in its
+*  object database, whether it's reachable from a branch or not, so going
+*  through all of its objects rather than walking commits finds them all
+*  without checking anything out.  A blob can go into a filter as its
+*  contents or as its object ID, which is the SHA-1 of
+*
+*    blob <length>\0<contents>
+*
+*  and so can be worked out for a file in a working tree too, if git didn't
+*  change its line endings on the way in.
+*/
+use gix::ObjectId;
+use gix::objs::Kind;
+use std::path::Path;
+
+
+/// A local git repository, read without running git
+pub struct GitRepo {
+    repo: gix::Repository,
+}
+
+
+impl GitRepo {
+    /// The repository at `path`, either its working tree or its .git
+    pub fn open(path: &Path) -> Result<GitRepo, String> {
+        match gix::open(path) {
+            Ok(repo) => Ok(GitRepo {repo}),
+            Err(err) => Err(format!("Not a git repository ({})", err)),
+        }
+    }
+
+
+    /// The IDs of every blob in the repository, each once, in order
+    pub fn blob_ids(&self) -> Result<Vec<ObjectId>, String> {
+        let objects = self.repo.objects.iter().map_err(|err| format!("Unable to list objects ({})", err))?;
+        let mut ids = Vec::new();
+        for id in objects {
+            let id = id.map_err(|err| format!("Unable to list objects ({})", err))?;
+            let header = self.repo.find_header(id).map_err(|err| format!("Unable to read object {} ({})", id, err))?;
+            if header.kind() == Kind::Blob {
+                ids.push(id);
+            }
+        }
+        /* Objects can be in more than one pack, or packed and loose */
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+
+    /// The contents of the blob `id`
+    pub fn blob(&self, id: ObjectId) -> Result<Vec<u8>, String> {
+        match self.repo.find_object(id) {
+            Ok(object) => Ok(object.detach().data),
+            Err(err) => Err(format!("Unable to read blob {} ({})", id, err)),
+        }
+    }
+}
+
+
+/// The object ID git gives a blob of `contents`, like `git hash-object`
+pub fn blob_id(contents: &[u8]) -> Result<[u8; 20], String> {
+    let oid = gix::objs::compute_hash(gix::hash::Kind::Sha1, Kind::Blob, contents)
+        .map_err(|err| format!("Unable to hash blob ({})", err))?;
+    let mut id = [0; 20];
+    id.copy_from_slice(oid.as_bytes());
+    Ok(id)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    /* `git hash-object` of "known" */
+    const KNOWN_ID: &str = "b1f0701cad56e69c2c65d05aa04e8a8998de6c7d";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> bool {
+        Command::new("git")
+            .arg("-C").arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    #[test]
+    fn test_blob_id() {
+        assert_eq!(hex(&blob_id(b"known").unwrap()), KNOWN_ID);
+        assert_eq!(hex(&blob_id(b"").unwrap()), "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391");
+    }
+
+    #[test]
+    fn test_blobs_across_history() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bloom-cli-git-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        /* Like the functional test, this needs git to make a repository */
+        assert!(git(&dir, &["init", "-q"]), "Unable to run git init, is git installed?");
+        fs::write(dir.join("a"), b"known").unwrap();
+        assert!(git(&dir, &["add", "a"]));
+        assert!(git(&dir, &["commit", "-q", "-m", "first"]));
+        fs::write(dir.join("a"), b"changed").unwrap();
+        fs::write(dir.join("b"), b"known").unwrap();
+        assert!(git(&dir, &["add", "a", "b"]));
+        assert!(git(&dir, &["commit", "-q", "-m", "second"]));
+        /* Packed objects are found as well as loose ones */
+        assert!(git(&dir, &["gc", "-q"]));
+        fs::write(dir.join("a"), b"uncommitted").unwrap();
+
+        let repo = GitRepo::open(&dir).unwrap();
+        let ids = repo.blob_ids().unwrap();
+        let mut contents: Vec<Vec<u8>> = ids.iter().map(|id| repo.blob(*id).unwrap()).collect();
+        contents.sort();
+        assert_eq!(contents, vec![b"changed".to_vec(), b"known".to_vec()]);
+        assert!(ids.iter().all(|id| blob_id(&repo.blob(*id).unwrap()).unwrap() == id.as_bytes()));
+        assert!(ids.iter().any(|id| id.to_string() == KNOWN_ID));
+
+        assert!(GitRepo::open(Path::new("/nonexistent")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
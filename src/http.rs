@@ -0,0 +1,340 @@
+/* An HTTP front end on a server's filters, for clients that can't use its
+*  Unix socket
+*
+*    POST /insert        {"filter": F, "item": I} or {"filter": F, "items": [I, ...]}
+*                        -> {"inserted": N}
+*    POST /query         {"filter": F, "item": I} -> {"item": I, "in": B}
+*    POST /query/batch   {"filter": F, "items": [I, ...]}
+*                        -> {"results": [{"item": I, "in": B}, ...]}
+*    GET  /stats         -> {"filters": [{"path": F, "m": ..., ...}, ...]}
+*    POST /snapshot      -> {"flushed": true}, once everything's on disk
+*
+*  An item is a string, inserted as its UTF-8 bytes, or {"hex": "..."} for
+*  any other bytes.  "filter" can be left out if only one filter is being
+*  served.  Failures are {"error": "..."} with a 4xx or 5xx status, and
+*  bodies over MAX_BODY_LEN are turned away with a 413.
+*/
+use serde_json::json;
+use serde_json::Value;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::Header;
+use tiny_http::Request;
+use tiny_http::Response;
+use tiny_http::Server;
+
+use crate::server::FilterSet;
+
+
+/// Longest request body read
+pub const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+
+/// What went wrong, as an HTTP status and message
+type Failure = (u16, String);
+
+
+fn bad_request(message: String) -> Failure {
+    (400, message)
+}
+
+
+/// The filter a request body names, or the only one if it doesn't
+fn filter_filename(filters: &FilterSet, body: &Value) -> Result<String, Failure> {
+    match body.get("filter") {
+        Some(Value::String(filter_filename)) => {
+            if filters.serves(filter_filename) {
+                Ok(filter_filename.clone())
+            }
+            else {
+                Err((404, format!("Not serving a filter at '{}'", filter_filename)))
+            }
+        },
+        Some(_) => Err(bad_request("\"filter\" must be a string".to_owned())),
+        None => {
+            let mut paths = filters.paths();
+            match (paths.next(), paths.next()) {
+                (Some(path), None) => Ok(path.to_string_lossy().into_owned()),
+                _ => Err(bad_request("\"filter\" is needed when serving more than one".to_owned())),
+            }
+        },
+    }
+}
+
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("'{}' isn't an even number of hex digits", hex));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|err| format!("'{}': {}", hex, err)))
+        .collect()
+}
+
+
+/// The bytes an item in a request stands for
+fn item_bytes(item: &Value) -> Result<Vec<u8>, Failure> {
+    match item {
+        Value::String(string) => Ok(string.as_bytes().to_vec()),
+        Value::Object(object) => match object.get("hex") {
+            Some(Value::String(hex)) => decode_hex(hex).map_err(bad_request),
+            _ => Err(bad_request("Items must be strings or {\"hex\": \"...\"}".to_owned())),
+        },
+        _ => Err(bad_request("Items must be strings or {\"hex\": \"...\"}".to_owned())),
+    }
+}
+
+
+/// The item(s) in a request body, from "item" if `single` or "items"
+fn items(body: &Value, single: bool) -> Result<Vec<Value>, Failure> {
+    if single {
+        if let Some(item) = body.get("item") {
+            return Ok(vec![item.clone()]);
+        }
+        if body.get("items").is_none() {
+            return Err(bad_request("Expected \"item\" or \"items\"".to_owned()));
+        }
+    }
+    match body.get("items") {
+        Some(Value::Array(items)) => Ok(items.clone()),
+        _ => Err(bad_request("Expected \"items\" to be an array".to_owned())),
+    }
+}
+
+
+fn query_results(filters: &FilterSet, filter: &str, items: Vec<Value>) -> Result<Vec<Value>, Failure> {
+    items.into_iter().map(|item| {
+        let hashes = filters.item_hashes(filter, &item_bytes(&item)?).map_err(bad_request)?;
+        match filters.query(filter, &hashes) {
+            Ok(is_in) => Ok(json!({"item": item, "in": is_in})),
+            Err(err) => Err((500, err)),
+        }
+    }).collect()
+}
+
+
+/// The status and JSON body to answer a request with
+pub fn handle(filters: &FilterSet, method: &str, url: &str, body: &str) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or("");
+    let parsed = || -> Result<Value, Failure> {
+        serde_json::from_str(body).map_err(|err| bad_request(format!("Bad JSON ({})", err)))
+    };
+
+    let result: Result<Value, Failure> = match (method, path) {
+        ("POST", "/insert") => parsed().and_then(|body| {
+            let filter = filter_filename(filters, &body)?;
+            /* Every item's checked before any goes in, so a bad one
+            *  doesn't leave the rest half inserted */
+            let all_hashes = items(&body, true)?.iter()
+                .map(|item| filters.item_hashes(&filter, &item_bytes(item)?).map_err(bad_request))
+                .collect::<Result<Vec<_>, Failure>>()?;
+            for hashes in all_hashes.iter() {
+                filters.insert(&filter, hashes).map_err(|err| (500, err))?;
+            }
+            Ok(json!({"inserted": all_hashes.len()}))
+        }),
+        ("POST", "/query") => parsed().and_then(|body| {
+            let filter = filter_filename(filters, &body)?;
+            let item = match body.get("item") {
+                Some(item) => item.clone(),
+                None => {
+                    return Err(bad_request("Expected \"item\"".to_owned()));
+                },
+            };
+            let mut results = query_results(filters, &filter, vec![item])?;
+            Ok(results.remove(0))
+        }),
+        ("POST", "/query/batch") => parsed().and_then(|body| {
+            let filter = filter_filename(filters, &body)?;
+            let results = query_results(filters, &filter, items(&body, false)?)?;
+            Ok(json!({"results": results}))
+        }),
+        ("GET", "/stats") => {
+            let stats: Vec<Value> = filters.stats().iter().map(|stats| json!({
+                "path": stats.path.to_string_lossy(),
+                "m": usize::from(stats.m),
+                "k": stats.num_hashes,
+                "bits_set": stats.bits_set,
+                "fill_ratio": stats.fill_ratio(),
+                "estimated_items": stats.estimated_items().round(),
+                "dirty": stats.dirty,
+            })).collect();
+            Ok(json!({"filters": stats}))
+        },
+        ("POST", "/snapshot") => match filters.flush() {
+            Ok(()) => Ok(json!({"flushed": true})),
+            Err(err) => Err((500, err)),
+        },
+        (_, "/insert" | "/query" | "/query/batch" | "/stats" | "/snapshot") => {
+            Err((405, format!("{} isn't allowed on {}", method, path)))
+        },
+        _ => Err((404, format!("No such endpoint {}", path))),
+    };
+
+    match result {
+        Ok(json) => (200, json),
+        Err((status, message)) => (status, json!({"error": message})),
+    }
+}
+
+
+pub fn bind(address: &str) -> Result<Server, String> {
+    Server::http(address).map_err(|err| {
+        format!("Unable to listen for HTTP on '{}' ({})", address, err)
+    })
+}
+
+
+/// Answer HTTP requests on `http_server` forever, each on a thread of its
+/// own so one whose body is slow in coming doesn't hold up the rest
+pub fn serve(http_server: Server, filters: Arc<FilterSet>) {
+    for request in http_server.incoming_requests() {
+        let filters = Arc::clone(&filters);
+        thread::spawn(move || answer(request, &filters));
+    }
+}
+
+
+fn answer(mut request: Request, filters: &FilterSet) {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let mut body = String::new();
+    let too_big = (413, json!({"error": format!("Body is over {} bytes", MAX_BODY_LEN)}));
+    let (status, json) = if request.body_length().is_some_and(|len| len > MAX_BODY_LEN) {
+        too_big
+    }
+    else {
+        /* Chunked bodies don't say how long they are up front */
+        match request.as_reader().take(MAX_BODY_LEN as u64 + 1).read_to_string(&mut body) {
+            Ok(len) if len > MAX_BODY_LEN => too_big,
+            Ok(_) => handle(filters, request.method().as_str(), request.url(), &body),
+            Err(err) => (400, json!({"error": format!("Unreadable body ({})", err)})),
+        }
+    };
+    let response = Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    if let Err(err) = request.respond(response) {
+        eprintln!("ERROR: {}", err);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::num::NonZeroUsize;
+
+    fn temp_filter(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-http-{}-{}", name, std::process::id()));
+        let m = NonZeroUsize::new(4096).unwrap();
+        assert!(format::write_filter(File::create(&path).unwrap(), m, &[0; 64]).is_ok());
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_handle() {
+        let filter = temp_filter("handle");
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+
+        let (status, json) = handle(&filters, "POST", "/query", r#"{"item": "known"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(json, json!({"item": "known", "in": false}));
+
+        let (status, json) = handle(&filters, "POST", "/insert", &json!({
+            "filter": filter,
+            "items": ["known", {"hex": "00ff"}],
+        }).to_string());
+        assert_eq!((status, json), (200, json!({"inserted": 2})));
+
+        let (status, json) = handle(&filters, "POST", "/query/batch", r#"{
+            "items": ["known", "unknown", {"hex": "00FF"}]
+        }"#);
+        assert_eq!(status, 200);
+        assert_eq!(json, json!({"results": [
+            {"item": "known", "in": true},
+            {"item": "unknown", "in": false},
+            {"item": {"hex": "00FF"}, "in": true},
+        ]}));
+
+        let (status, json) = handle(&filters, "GET", "/stats", "");
+        assert_eq!(status, 200);
+        assert_eq!(json["filters"][0]["m"], 4096);
+        assert_eq!(json["filters"][0]["dirty"], true);
+        assert_eq!(json["filters"][0]["estimated_items"], 2.0);
+
+        assert_eq!(handle(&filters, "POST", "/snapshot", ""), (200, json!({"flushed": true})));
+        let (_, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert!(crate::is_in_filter(b"known", &bits, NonZeroUsize::new(4096).unwrap()).unwrap());
+
+        /* Failures */
+        assert_eq!(handle(&filters, "POST", "/query", "{").0, 400);
+        assert_eq!(handle(&filters, "POST", "/query", "{}").0, 400);
+        assert_eq!(handle(&filters, "POST", "/query", r#"{"item": 5}"#).0, 400);
+        assert_eq!(handle(&filters, "POST", "/query", r#"{"item": {"hex": "0"}}"#).0, 400);
+        assert_eq!(handle(&filters, "POST", "/query", r#"{"item": "a", "filter": "/nope"}"#).0, 404);
+        assert_eq!(handle(&filters, "GET", "/query", "").0, 405);
+        assert_eq!(handle(&filters, "GET", "/nope", "").0, 404);
+        assert!(handle(&filters, "GET", "/nope", "").1["error"].is_string());
+
+        /* Nothing's inserted if any item is bad */
+        let (status, _) = handle(&filters, "POST", "/insert", r#"{"items": ["first", 5]}"#);
+        assert_eq!(status, 400);
+        let (_, json) = handle(&filters, "POST", "/query", r#"{"item": "first"}"#);
+        assert_eq!(json["in"], false);
+
+        fs::remove_file(crate::lock::lock_path(std::path::Path::new(&filter))).unwrap();
+        fs::remove_file(&filter).unwrap();
+    }
+
+    #[test]
+    fn test_serve_over_tcp() {
+        let filter = temp_filter("tcp");
+        let filters = Arc::new(FilterSet::load(std::slice::from_ref(&filter)).unwrap());
+        let http_server = bind("127.0.0.1:0").unwrap();
+        let address = http_server.server_addr().to_ip().unwrap();
+        thread::spawn(move || serve(http_server, filters));
+
+        /* A client that never finishes sending its body */
+        let mut stalled = TcpStream::connect(address).unwrap();
+        write!(stalled, "POST /query HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100000\r\n\r\n{{").unwrap();
+        thread::sleep(std::time::Duration::from_millis(100));
+
+        /* ...doesn't hold up anyone else */
+        let body = r#"{"item": "known"}"#;
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10))).unwrap();
+        write!(
+            stream,
+            "POST /query HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("application/json"));
+        assert!(response.ends_with(r#"{"in":false,"item":"known"}"#));
+
+        /* Turned away without being read */
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST /insert HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            MAX_BODY_LEN + 1
+        ).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"));
+
+        drop(stalled);
+        fs::remove_file(crate::lock::lock_path(std::path::Path::new(&filter))).unwrap();
+        fs::remove_file(&filter).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+/* Kinds of filter other than a plain bit array
+*
+*  A filter's kind and its settings are kept in its header (see format.rs),
+*  so inserts and queries of it work the same way whoever makes them.  Kinds
+*  can change more than bits when they're inserted into, like when a
+*  rotating filter last rotated or where a stable one's generator is up to,
+*  so whatever inserts into them rewrites the whole filter, header and all.
+*  They're always read whole, never mapped or read lazily, and can't be
+*  served.
+*/
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::expiring::Expiry;
+use crate::rotating::Rotation;
+use crate::stable::Stability;
+use crate::{hashes_in_filter, num_u64s, test_and_insert_hashes, ItemHashes};
+
+/* How kinds are numbered in a header */
+const ROTATING: u32 = 1;
+const STABLE: u32 = 2;
+const EXPIRING: u32 = 3;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Kind {
+    #[default]
+    Plain,
+    /// Several generations, the oldest dropped every so often (see
+    /// rotating.rs)
+    Rotating(Rotation),
+    /// Small counters that decay as items go in (see stable.rs)
+    Stable(Stability),
+    /// Cells that expire some time after they're set (see expiring.rs)
+    Expiring(Expiry),
+}
+
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Plain => write!(f, "plain"),
+            Kind::Rotating(rotation) => write!(f, "{}", rotation),
+            Kind::Stable(stability) => write!(f, "{}", stability),
+            Kind::Expiring(expiry) => write!(f, "{}", expiry),
+        }
+    }
+}
+
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Plain => "plain",
+            Kind::Rotating(_) => "rotating",
+            Kind::Stable(_) => "stable",
+            Kind::Expiring(_) => "expiring",
+        }
+    }
+
+
+    /// The kind numbered `number` in a header, with its `fields`, for a
+    /// filter of `m` bits or counters
+    pub fn from_fields(number: u32, fields: [u64; 3], m: NonZeroUsize) -> Result<Kind, String> {
+        match number {
+            ROTATING => {
+                let generations = u32::try_from(fields[0]).unwrap_or(u32::MAX);
+                let rotation = Rotation::new(generations, fields[1], fields[2])?;
+                Ok(Kind::Rotating(rotation))
+            },
+            STABLE => {
+                let max = u8::try_from(fields[0]).unwrap_or(u8::MAX);
+                let decrements = u32::try_from(fields[1]).unwrap_or(0);
+                Ok(Kind::Stable(Stability::new(max, decrements, fields[2], m)?))
+            },
+            EXPIRING => Ok(Kind::Expiring(Expiry::new(fields[0], fields[1])?)),
+            _ => Err(format!("Unknown kind of filter {}", number)),
+        }
+    }
+
+
+    /// This kind's number and fields in a header, the inverse of
+    /// `from_fields`
+    pub fn to_fields(self) -> (u32, [u64; 3]) {
+        match self {
+            Kind::Plain => (0, [0; 3]),
+            Kind::Rotating(rotation) => {
+                (ROTATING, [rotation.generations as u64, rotation.interval, rotation.rotated_at])
+            },
+            Kind::Stable(stability) => {
+                (STABLE, [stability.max as u64, stability.decrements as u64, stability.state])
+            },
+            Kind::Expiring(expiry) => (EXPIRING, [expiry.ttl, expiry.epoch, 0]),
+        }
+    }
+
+
+    /// Are `self` and `other` the same kind with the same settings, whatever
+    /// state each is in?
+    pub fn matches(&self, other: &Kind) -> bool {
+        match (self, other) {
+            (Kind::Rotating(a), Kind::Rotating(b)) => {
+                a.generations == b.generations && a.interval == b.interval
+            },
+            (Kind::Stable(a), Kind::Stable(b)) => a.max == b.max && a.decrements == b.decrements,
+            /* A TTL is only a default, others can be given per insert */
+            (Kind::Expiring(_), Kind::Expiring(_)) => true,
+            _ => self == other,
+        }
+    }
+
+
+    /// How many u64s a filter of this kind with `m` bits (or counters, or
+    /// cells) takes up, or an error if that's more than there could be
+    pub fn body_u64s(&self, m: NonZeroUsize) -> Result<usize, String> {
+        match self {
+            Kind::Plain => Ok(num_u64s(m)),
+            Kind::Rotating(rotation) => (rotation.generations as usize).checked_mul(num_u64s(m)).ok_or_else(|| {
+                format!("{} generations of m = {} is too big", rotation.generations, m)
+            }),
+            Kind::Stable(_) => Ok(Stability::body_u64s(m)),
+            Kind::Expiring(_) => Ok(Expiry::body_u64s(m)),
+        }
+    }
+
+
+    /// Is an item with `hashes` (probably) in `filter`, as of `now`?
+    pub fn contains(&self, hashes: &ItemHashes, filter: &[u64], m: NonZeroUsize, now: u64) -> Result<bool, String> {
+        match self {
+            Kind::Plain => hashes_in_filter(hashes, filter, m),
+            Kind::Rotating(rotation) => rotation.contains(hashes, filter, m, now),
+            Kind::Stable(stability) => stability.contains(hashes, filter, m),
+            Kind::Expiring(expiry) => expiry.contains(hashes, filter, m, now),
+        }
+    }
+
+
+    /// Insert an item with `hashes` into `filter` at `now`, returning
+    /// whether it was (probably) in already.  An expiring filter keeps it
+    /// for `ttl` seconds if that's given, and its own TTL otherwise.
+    pub fn test_and_insert(
+        &mut self,
+        hashes: &ItemHashes,
+        filter: &mut [u64],
+        m: NonZeroUsize,
+        now: u64,
+        ttl: Option<u64>
+    ) -> Result<bool, String> {
+        match self {
+            Kind::Plain => test_and_insert_hashes(hashes, filter, m),
+            Kind::Rotating(rotation) => rotation.test_and_insert(hashes, filter, m, now),
+            Kind::Stable(stability) => stability.test_and_insert(hashes, filter, m),
+            Kind::Expiring(expiry) => {
+                expiry.test_and_insert(hashes, filter, m, now, ttl.unwrap_or(expiry.ttl))
+            },
+        }
+    }
+}
+
+
+/// Seconds since the epoch, which is what kinds keep time in
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+
+    #[test]
+    fn test_fields() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let rotating = Kind::Rotating(Rotation::new(24, 3600, 1_000_000).unwrap());
+        let (number, fields) = rotating.to_fields();
+        assert_eq!(Kind::from_fields(number, fields, m), Ok(rotating));
+        assert!(Kind::from_fields(ROTATING, [0, 3600, 0], m).is_err());
+        assert!(Kind::from_fields(ROTATING, [1 << 40, 3600, 0], m).is_err());
+        assert!(Kind::from_fields(99, [0; 3], m).is_err());
+
+        let stable = Kind::Stable(Stability::new(7, 10, 12345, m).unwrap());
+        let (number, fields) = stable.to_fields();
+        assert_eq!(Kind::from_fields(number, fields, m), Ok(stable));
+        assert!(Kind::from_fields(STABLE, [16, 10, 0], m).is_err());
+        assert!(Kind::from_fields(STABLE, [7, 1 << 40, 0], m).is_err());
+        assert!(Kind::from_fields(STABLE, [7, 1001, 0], m).is_err());
+        assert!(stable.matches(&Kind::Stable(Stability::new(7, 10, 0, m).unwrap())));
+        assert!(!stable.matches(&Kind::Stable(Stability::new(7, 20, 12345, m).unwrap())));
+
+        let expiring = Kind::Expiring(Expiry::new(3600, 1_000_000).unwrap());
+        let (number, fields) = expiring.to_fields();
+        assert_eq!(Kind::from_fields(number, fields, m), Ok(expiring));
+        assert!(Kind::from_fields(EXPIRING, [0, 1_000_000, 0], m).is_err());
+        assert!(expiring.matches(&Kind::Expiring(Expiry::new(3600, 2_000_000).unwrap())));
+        assert!(expiring.matches(&Kind::Expiring(Expiry::new(60, 1_000_000).unwrap())));
+        assert!(!expiring.matches(&stable));
+
+        let later = Kind::Rotating(Rotation::new(24, 3600, 2_000_000).unwrap());
+        assert!(rotating.matches(&later) && rotating != later);
+        assert!(!rotating.matches(&Kind::Plain));
+        assert!(!rotating.matches(&Kind::Rotating(Rotation::new(24, 60, 1_000_000).unwrap())));
+    }
+
+    #[test]
+    fn test_plain_kind() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let mut kind = Kind::Plain;
+        let mut filter = vec![0; kind.body_u64s(m).unwrap()];
+        let known = item_hashes(b"known");
+        assert_eq!(kind.test_and_insert(&known, &mut filter, m, 0, None), Ok(false));
+        assert_eq!(kind.test_and_insert(&known, &mut filter, m, 0, None), Ok(true));
+        assert_eq!(kind.contains(&known, &filter, m, u64::MAX), Ok(true));
+        assert_eq!(Kind::Rotating(Rotation::new(3, 0, 0).unwrap()).body_u64s(m), Ok(3 * filter.len()));
+        let huge = NonZeroUsize::new(1 << 63).unwrap();
+        assert!(Kind::Rotating(Rotation::new(1024, 0, 0).unwrap()).body_u64s(huge).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+/* A filter that's left on disk and read a word at a time
+*
+*  For a single query against a big filter, reading (or even mapping) the
+*  whole thing is wasted work: only NUM_HASHES u64s matter.  A `LazyFilter`
+*  reads just the header when it's opened and then `pread`s the words each
+*  query needs.
+*
+*  Lazy queries can't check the checksum without reading everything, so
+*  they don't; --check reads the whole filter instead.  Like mapped ones,
+*  they're only for plain filters, though a `LazyFilter` of any kind can be
+*  opened to read its header.
+*/
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use crate::format;
+use crate::format::{Header, MAX_HEADER_LEN};
+use crate::kind::Kind;
+use crate::{bit_positions, bit_set};
+
+
+/// A handle on a filter file that reads only what it needs when queried
+#[derive(Debug)]
+pub struct LazyFilter {
+    file: File,
+    header: Header,
+    body_offset: u64,
+}
+
+
+impl LazyFilter {
+    /// Read and check the header of the filter in `file`, which should stay
+    /// locked (see lock.rs) for as long as the `LazyFilter`'s used
+    pub fn new(file: File) -> Result<LazyFilter, String> {
+        let total_len = match file.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(err) => {
+                return Err(err.to_string());
+            },
+        };
+
+        let mut prefix = vec![0; MAX_HEADER_LEN.min(total_len)];
+        if let Err(err) = file.read_exact_at(&mut prefix, 0) {
+            return Err(err.to_string());
+        }
+        let (header, body_offset) = format::parse_header(&prefix, total_len)?;
+
+        Ok(LazyFilter {file, header, body_offset: body_offset as u64})
+    }
+
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+
+    /// Back to the file
+    pub fn into_file(self) -> File {
+        self.file
+    }
+
+
+    /// The u64 at `index` in the bit array
+    fn word(&self, index: usize) -> Result<u64, String> {
+        let mut buffer = [0; 8];
+        let offset = self.body_offset + index as u64 * 8;
+        match self.file.read_exact_at(&mut buffer, offset) {
+            Ok(()) => Ok(u64::from_be_bytes(buffer)),
+            Err(err) => Err(format!("Unable to read u64 at index {} ({})", index, err)),
+        }
+    }
+
+
+    /// Is `bytes` (probably) in the filter, which must be a plain one?
+    pub fn contains(&self, bytes: &[u8]) -> Result<bool, String> {
+        if self.header.kind != Kind::Plain {
+            return Err(format!("Can't query a {} filter lazily", self.header.kind.name()));
+        }
+        for (whichint, whichbit) in bit_positions(bytes, self.header.m) {
+            if !bit_set(self.word(whichint)?, whichbit)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_lazy_filter() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-lazy-{}", std::process::id()));
+        let m = NonZeroUsize::new(5000).unwrap();
+        let mut filter = vec![0; crate::num_u64s(m)];
+        for i in 0..100 {
+            assert!(crate::filter_insert(i.to_string().as_bytes(), &mut filter, m).is_ok());
+        }
+        assert!(format::write_filter(File::create(&path).unwrap(), m, &filter).is_ok());
+
+        let lazy = LazyFilter::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(lazy.header().m, m);
+        for i in 0..200 {
+            let as_string = i.to_string();
+            assert_eq!(
+                lazy.contains(as_string.as_bytes()),
+                crate::is_in_filter(as_string.as_bytes(), &filter, m)
+            );
+        }
+
+        /* A truncated file is caught when it's opened */
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(format::HEADER_LEN as u64 + 8).unwrap();
+        assert!(LazyFilter::new(File::open(&path).unwrap()).is_err());
+        file.set_len(3).unwrap();
+        assert!(LazyFilter::new(File::open(&path).unwrap()).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+/* Locks on filters
+*
+*  A filter is locked through a `<filter>.lock` file beside it rather than
+*  through the filter itself, because rewriting a filter renames a new file
+*  into its place (see `format::replace_filter`), and a lock on the old file
+*  wouldn't keep anyone off the new one.  So the lock has to be taken before
+*  the filter's opened, to be sure of opening the current file.  Lock files
+*  are left behind once they've been made.
+*
+*  A shared lock on a filter whose lock file doesn't exist yet and can't be
+*  made, say in a directory we can only read, goes without.
+*/
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+
+/// A lock on a filter, held until it's dropped
+#[derive(Debug)]
+pub struct FilterLock {
+    _file: Option<File>,
+}
+
+
+/// The lock file for the filter at `filter_path`, beside wherever any
+/// symlinks lead so every name for the filter shares it
+pub fn lock_path(filter_path: &Path) -> PathBuf {
+    let path = fs::canonicalize(filter_path).unwrap_or_else(|_| filter_path.to_path_buf());
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+
+/// Lock the filter at `filter_path`, shared or `exclusive`ly, waiting for
+/// anyone else's lock to be released if `wait` and failing with
+/// `WouldBlock` otherwise
+pub fn lock(filter_path: &Path, exclusive: bool, wait: bool) -> Result<FilterLock, TryLockError> {
+    let lock_path = lock_path(filter_path);
+    let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_path) {
+        Ok(file) => file,
+        Err(_) if !exclusive => match File::open(&lock_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(FilterLock {_file: None});
+            },
+            Err(err) => {
+                return Err(TryLockError::Error(err));
+            },
+        },
+        Err(err) => {
+            return Err(TryLockError::Error(err));
+        },
+    };
+
+    let locked = match (exclusive, wait) {
+        (true, true) => file.lock().map_err(TryLockError::Error),
+        (true, false) => file.try_lock(),
+        (false, true) => file.lock_shared().map_err(TryLockError::Error),
+        (false, false) => file.try_lock_shared(),
+    };
+    locked.map(|()| FilterLock {_file: Some(file)})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-lock-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+
+        let shared = lock(&path, false, false).unwrap();
+        assert!(lock(&path, false, false).is_ok());
+        assert!(matches!(lock(&path, true, false), Err(TryLockError::WouldBlock)));
+        drop(shared);
+        let exclusive = lock(&path, true, false).unwrap();
+        assert!(matches!(lock(&path, false, false), Err(TryLockError::WouldBlock)));
+
+        /* Still locked once the filter's been replaced by another file */
+        fs::write(path.with_extension("new"), b"").unwrap();
+        fs::rename(path.with_extension("new"), &path).unwrap();
+        assert!(matches!(lock(&path, true, false), Err(TryLockError::WouldBlock)));
+        drop(exclusive);
+        assert!(lock(&path, true, false).is_ok());
+
+        fs::remove_file(lock_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
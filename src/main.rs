@@ -1,767 +1,1963 @@
 use argh::FromArgs;
+use bloom_cli::archive;
+use bloom_cli::chunk;
+use bloom_cli::dupes::{self, DupeFinder, Found};
+use bloom_cli::expiring::Expiry;
+use bloom_cli::format;
+use bloom_cli::git::GitRepo;
+use bloom_cli::http;
+use bloom_cli::kind::{unix_now, Kind};
+use bloom_cli::lazy::LazyFilter;
+use bloom_cli::lock::{self, FilterLock};
+use bloom_cli::mapped;
+use bloom_cli::normalize::Normalize;
+use bloom_cli::resp;
+use bloom_cli::rotating::Rotation;
+use bloom_cli::server;
+use bloom_cli::stable::{Stability, DEFAULT_CELL_MAX};
+use bloom_cli::view;
+use bloom_cli::view::View;
+use bloom_cli::watch::TreeWatcher;
+use bloom_cli::{bit_positions, item_hashes};
+use bloom_cli::M_NZ;
+use serde_json::json;
+use serde_json::Value;
+use std::env;
+use std::fmt::Display;
 use std::fs;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
 use std::io::Read;
-use std::io::Write;
 use std::num::NonZeroUsize;
 use std::path::Path;
+use std::path::PathBuf;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::process;
-use xxhash_rust::xxh32;
-
-macro_rules! vprintln {
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Print a diagnostic to stderr if running at `$level` or above
+macro_rules! log {
     ($level:expr, $($message:expr),+) => {
-        if $level {
+        if log_level() >= $level {
             eprintln!($($message ,)+);
         }
     };
 }
 
-/* Create an empty bloom filter 3321928 bits long
-*
-*  Approximately optimal m (# bits) and k (# hashes) for e error rate
-*  and n (# records):
-*
-*  m = -n * log e / (log 2)^2
-*  k = -log e
-*
-*  (logs base 2)
-*
-*  So for error rate of .01 and n = 500000
-*   
-*  m = 3321928 bits (fits in 51905 u64s)
-*  k = 7
-*/
-// TODO Somehow make these NonZeroUsize without running into needing to .into()
-// later since that's not allowed for const
-const M: usize = 3321928;
-const FILTER_NUM_BITS: usize = 51906;
-
-static M_NZ: NonZeroUsize = match NonZeroUsize::new(M) {
-    Some(good_num) => {
-        good_num
-    }
-    None => {
-        panic!("Internal error with non-zero number");
-    }
-};
-
-fn read_filter_from_disk(filename: &str) -> Result<Vec<u64>, String> {
-    let mut file = match File::open(filename) {
-        Ok(file) => BufReader::new(file),
-        Err(err) => {
-            return Err(format!("{}", err));
-        },
-    };
 
-    let mut to_return = Vec::<u64>::new();
-    let mut buffer = [0; 8];
+/// How results and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
 
-    loop {
-        match file.read(&mut buffer[..]) {
-            Ok(num_bytes_read) => {
-                if num_bytes_read < 8 {
-                    return Ok(to_return);
-                }
-                else {
-                    to_return.push(
-                        ((buffer[0] as u64) << 56) |
-                        ((buffer[1] as u64) << 48) |
-                        ((buffer[2] as u64) << 40) |
-                        ((buffer[3] as u64) << 32) |
-                        ((buffer[4] as u64) << 24) |
-                        ((buffer[5] as u64) << 16) |
-                        ((buffer[6] as u64) <<  8) |
-                        ((buffer[7] as u64) <<  0)
-                    );
-                }
-            },
-            Err(err) => {
-                return Err(format!("{}", err));
-            },
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<OutputFormat, String> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("'{}' isn't one of text or json", format)),
         }
     }
 }
 
 
-fn write_filter_to_disk(filename: &str, filter: &[u64]) -> Result<(), String> {
-    let mut file = match File::create(filename) {
-        Ok(file) => BufWriter::new(file),
-        Err(err) => {
-            return Err(err.to_string());
-        },
-    };
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Elementary bloom filter
+struct Args {
+    /// an existing bloom filter's filename or that of one to be created.
+    /// `-x` is to emphasize that this can overwrite an existing file.
+    #[argh(option, short='x')]
+    filter_filename: Option<String>,
 
-    for int in filter.iter() {
-        if file.write_all(&int.to_be_bytes()).is_err() {
-            return Err(format!("Failed to write to {}", filename));
-        }
-    }
+    /// file to (i)nsert into the filter
+    #[argh(option, short='i')]
+    file_to_insert: Option<String>,
+
+    /// file to (q)uery for in the filter, can be given more than once
+    #[argh(option, short='q')]
+    file_to_query: Vec<String>,
+
+    /// print nothing and exit 0 if the queried file is (probably) in the
+    /// filter, 1 if it's definitely not and 2 to 25 on errors (17 for bad
+    /// arguments), like `grep -q`.  Errors aren't printed either (as with
+    /// `grep -s` too), and -v is ignored.
+    #[argh(switch, short='s')]
+    quiet: bool,
+
+    /// with -s and several -q, exit 0 if any of them is in the filter rather
+    /// than only if all of them are
+    #[argh(switch)]
+    any: bool,
+
+    /// send verbose output to stderr, -vv for debugging output (hashes,
+    /// timings) too
+    #[argh(switch, short='v')]
+    verbose: u8,
+
+    /// block until another process's lock on the filter is released
+    /// (the default)
+    #[argh(switch)]
+    wait: bool,
+
+    /// fail immediately with exit code 18 if another process has the
+    /// filter locked
+    #[argh(switch)]
+    no_wait: bool,
+
+    /// send inserts and queries to the `bloom-cli serve` listening on this
+    /// Unix domain socket if it's serving the filter, and go to the filter
+    /// directly otherwise.  Defaults to $BLOOM_CLI_SOCKET.
+    #[argh(option)]
+    socket: Option<String>,
+
+    /// read and rewrite the whole filter instead of memory-mapping it
+    #[argh(switch)]
+    no_mmap: bool,
+
+    /// read the whole filter and check its checksum before using it, rather
+    /// than mapping it or, for a single query of a big filter, reading only
+    /// the u64s needed
+    #[argh(switch)]
+    check: bool,
+
+    /// text (the default) or json, which prints one JSON object per result
+    /// or error
+    #[argh(option, default = "OutputFormat::Text")]
+    format: OutputFormat,
+
+    /// with --format json, also give the bits in the filter that each item
+    /// hashes to
+    #[argh(switch)]
+    show_bits: bool,
+
+    /// what of each -i/-q file goes into the filter: contents (the default),
+    /// sha256 for its SHA-256 digest, git-oid for the object ID git would
+    /// give it, or any comma separated mix of those and its path, size,
+    /// mtime, mode, owner and inode
+    #[argh(option, default = "View::CONTENTS")]
+    view: View,
+
+    /// the -i/-q files are lists of SHA-256 digests like sha256sum prints,
+    /// each of which is an item, landing on the same bits as --view sha256
+    /// does for the files they're of
+    #[argh(switch)]
+    digest_list: bool,
+
+    /// for a new filter, what to do to text before it's hashed: any comma
+    /// separated mix of nfc, line-endings, whitespace and case.  It's kept in
+    /// the filter, so inserts and queries of it do the same.  case does full
+    /// Unicode case folding, so "ß" and "SS" match.
+    #[argh(option)]
+    normalize: Option<Normalize>,
+
+    /// for a new filter, make it a rotating one of this many generations,
+    /// which forgets items once the generation they went into is dropped by
+    /// `bloom-cli rotate`
+    #[argh(option)]
+    generations: Option<u32>,
+
+    /// with --generations, drop the oldest generation every this many
+    /// seconds rather than only when asked to
+    #[argh(option)]
+    rotate_interval: Option<u64>,
+
+    /// for a new filter, make it a stable one of small counters, this many
+    /// of them decremented at random on every insert, so it never fills up
+    /// however many items go in but forgets older ones
+    #[argh(option)]
+    stable_decay: Option<u32>,
+
+    /// with --stable-decay, what an item's counters are set to when it's
+    /// inserted, up to 15 (default 7); higher remembers items for longer
+    #[argh(option)]
+    stable_max: Option<u8>,
+
+    /// for a new filter, make it an expiring one, where items are forgotten
+    /// this many seconds after they were last inserted unless they're
+    /// inserted with another --ttl; for an existing one, how long to keep
+    /// the items being inserted
+    #[argh(option)]
+    ttl: Option<u64>,
+
+    /// the -i/-q files are tar (optionally gzipped) or zip archives, and
+    /// each file in them is an item
+    #[argh(switch)]
+    archive: bool,
+
+    /// cut each -i/-q item into content-defined chunks and insert each of
+    /// them, or give the fraction of them already in the filter
+    #[argh(switch)]
+    chunks: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
 
 
-    Ok(())
+#[derive(Debug)]
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Verify(VerifyArgs),
+    Serve(ServeArgs),
+    AddIfAbsent(AddIfAbsentArgs),
+    Dupes(DupesArgs),
+    Watch(WatchArgs),
+    GitIndex(GitIndexArgs),
+    Rotate(RotateArgs),
+    Compact(CompactArgs),
 }
 
 
-fn is_in_filter(bytes: &[u8], filter: &[u64], m: NonZeroUsize) -> Result<bool, String> {
-    let mut to_return = true;
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Check a filter's header, length and checksum, exiting 19 if it's corrupt
+#[argh(subcommand, name = "verify")]
+struct VerifyArgs {
+    /// the bloom filter to check
+    #[argh(option, short='x')]
+    filter_filename: String,
+}
 
-    /* Check each hash */
-    for hash_num in 0..8 {
-        let (whichint, whichbit) = bit_array_indices(
-            xxh32::xxh32(bytes, hash_num) as u64,
-            m
-        );
-        if let Ok(is_set) = bit_set(filter[whichint], whichbit) {
-            if !is_set {
-                to_return = false;
-            }
-        }
-        else {
-            return Err("Unable to set bit in filter".to_owned());
-        }
-    }
 
-    Ok(to_return)
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Clear the expired cells of an expiring filter, so expired items aren't
+/// kept on disk at all
+#[argh(subcommand, name = "compact")]
+struct CompactArgs {
+    /// the expiring filter
+    #[argh(option, short='x')]
+    filter_filename: String,
 }
 
 
-fn filter_insert(bytes: &[u8], filter: &mut [u64], m: NonZeroUsize) -> Result<(), String> {
-    for hash_num in 0..8 {
-        let hash:u32 = xxh32::xxh32(bytes, hash_num);
-        let (whichint, whichbit) = bit_array_indices(hash as u64, m);
-        match set_bit(filter[whichint], whichbit) {
-            Ok(newint) => {filter[whichint] = newint;},
-            Err(err) => {return Err(err)},
-        }
-    }
-    Ok(())
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Drop the oldest generation of a rotating filter and start an empty one
+#[argh(subcommand, name = "rotate")]
+struct RotateArgs {
+    /// the rotating filter
+    #[argh(option, short='x')]
+    filter_filename: String,
 }
 
 
-/// Is bit at index `bit_index` in `an_int` set?
-fn bit_set(an_int: u64, bit_index: u8) -> Result<bool, String> {
-    if bit_index > 63 {
-        Err(format!("{} > 63", bit_index))
-    }
-    else {
-        Ok((((an_int & (0x8000000000000000 >> bit_index)) >> (63 - bit_index)) & 0x1) == 1)
-    }
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Insert a file unless it's (probably) in the filter already, in one step,
+/// exiting 0 if it was added and 1 if it was already in
+#[argh(subcommand, name = "add-if-absent")]
+struct AddIfAbsentArgs {
+    /// the bloom filter, created if it doesn't exist
+    #[argh(option, short='x')]
+    filter_filename: String,
+
+    /// file to (i)nsert
+    #[argh(option, short='i')]
+    file_to_insert: String,
+
+    /// print nothing and only exit 0, 1 or 2 or more on errors.  Errors
+    /// aren't printed either, and -v is ignored.
+    #[argh(switch, short='s')]
+    quiet: bool,
 }
 
 
-/// Return result of setting bit at index `bit_index` in `an_int`
-fn set_bit(an_int: u64, bit_index: u8) -> Result<u64, String> {
-    if bit_index <= 63 {
-        Ok(an_int | (0x8000000000000000 >> bit_index))
-    }
-    else {
-        Err(format!("{} > 63", bit_index))
-    }
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Print the files under a directory that (probably) have the same contents
+/// as one before them.  With -s, exits 0 if there are any and 1 if not.
+#[argh(subcommand, name = "dupes")]
+struct DupesArgs {
+    /// the directory to look through
+    #[argh(positional)]
+    dir: String,
+
+    /// compare each candidate with the earlier files of the same size and
+    /// only print real duplicates, along with what they duplicate
+    #[argh(switch)]
+    confirm: bool,
 }
 
 
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Insert files into a filter as they're written to or moved in under a
+/// directory, until interrupted
+#[argh(subcommand, name = "watch")]
+struct WatchArgs {
+    /// the directory to watch
+    #[argh(positional)]
+    dir: String,
+
+    /// the bloom filter, created if it doesn't exist
+    #[argh(option, short='x')]
+    filter_filename: String,
 
-/// In `filter`, once you've chosen the appropriate u64, which particular
-/// bit should be flipped?
-/// `m` is number of bit sin the filter.
-fn bit_index(i: u64, m: NonZeroUsize) -> u8 {
-    let reduced = (i as usize) % usize::from(m);
-    return (reduced % 64) as u8;
+    /// seconds to gather files for before inserting them all at once
+    #[argh(option, default = "1")]
+    batch_interval: u64,
 }
 
 
-/// Which u64 in `filter` should `i` be in?
-/// `m` is number of bit sin the filter.
-fn u64_index(i: u64, m: NonZeroUsize) -> usize {
-    let reduced = (i as usize) % usize::from(m);
-    reduced / 64
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Insert every blob a git repository has ever held, as its contents or,
+/// with --view git-oid, its object ID.  Query a working-tree file with the
+/// same --view to ask whether it matches any of them.
+#[argh(subcommand, name = "git-index")]
+struct GitIndexArgs {
+    /// the repository, its working tree or its .git directory
+    #[argh(positional)]
+    repo: String,
+
+    /// the bloom filter, created if it doesn't exist
+    #[argh(option, short='x')]
+    filter_filename: String,
 }
 
 
-/// Given bit number i, which u64 should it be in and within that u64, which
-/// bit should it be
-/// `m` is number of bits in the filter.
-fn bit_array_indices(i: u64, m: NonZeroUsize) -> (usize, u8) {
-    return (u64_index(i, m), bit_index(i, m));
+#[derive(Debug)]
+#[derive(FromArgs)]
+/// Keep filters in memory and answer insert and query requests for them
+/// over a Unix domain socket, HTTP and/or the Redis protocol
+#[argh(subcommand, name = "serve")]
+struct ServeArgs {
+    /// the Unix domain socket to listen on, defaults to $BLOOM_CLI_SOCKET
+    #[argh(option)]
+    socket: Option<String>,
+
+    /// a bloom filter to serve, can be given more than once
+    #[argh(option, short='x')]
+    filter_filename: Vec<String>,
+
+    /// also answer JSON requests over HTTP at this address, e.g.
+    /// 127.0.0.1:8080
+    #[argh(option)]
+    http: Option<String>,
+
+    /// also answer RedisBloom BF.* commands over the Redis protocol at this
+    /// address, e.g. 127.0.0.1:6379
+    #[argh(option)]
+    resp: Option<String>,
+
+    /// log inserts to this file before acknowledging them, and replay it on
+    /// startup, so none are lost if the server dies between flushes
+    #[argh(option)]
+    wal: Option<String>,
+
+    /// seconds between writing changed filters back to disk and reading in
+    /// what's been inserted into the files directly (default 5)
+    #[argh(option, default = "5")]
+    flush_interval: u64,
+
+    /// send verbose output to stderr, -vv for debugging output (hashes,
+    /// timings) too
+    #[argh(switch, short='v')]
+    verbose: u8,
 }
 
 
+/// Unless --check is given, a single query of a filter at least this big
+/// reads only the u64s needed rather than reading or mapping the whole thing
+const LAZY_QUERY_MIN_LEN: u64 = 64 * 1024;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Environment variable naming the server's socket if --socket isn't given
+const SOCKET_ENV_VAR: &str = "BLOOM_CLI_SOCKET";
 
+/// How many git blobs are read and inserted at a time
+const GIT_BATCH_LEN: usize = 1024;
 
-    /// How many u64s does it take to store `m` bits?
-    fn num_u64s(m: NonZeroUsize) -> usize {
-        (usize::from(m) - 1)/ 64 + 1
-    }
+/// Exit code for bad arguments, which is what conflicting ones always exited
+/// with.  Errors all exit above 1 so that 1 is free to mean "not in the
+/// filter" with -s.
+const USAGE_EXIT_CODE: i32 = 17;
 
+/// Options of any command that take a value, after which an argument isn't
+/// a flag even if it looks like one
+const VALUED_OPTIONS: &[&str] = &[
+    "-x", "-i", "-q", "--filter-filename", "--file-to-insert", "--file-to-query",
+    "--socket", "--format", "--view", "--normalize", "--generations",
+    "--rotate-interval", "--stable-decay", "--stable-max", "--ttl",
+    "--batch-interval", "--http", "--resp", "--wal", "--flush-interval",
+];
 
-    #[test]
-    fn test_num_u64s() {
-        for m in 1..=64 {
-            assert_eq!(num_u64s(NonZeroUsize::new(m).unwrap()), 1);
-        }
-        for m in 65..=128 {
-            assert_eq!(num_u64s(NonZeroUsize::new(m).unwrap()), 2);
-        }
-        for m in 129..=192 {
-            assert_eq!(num_u64s(NonZeroUsize::new(m).unwrap()), 3);
-        }
-    }
 
-    #[test]
-    fn test_u64_index() {
+/// Set once from --format before anything is printed
+static OUTPUT_FORMAT: OnceLock<OutputFormat> = OnceLock::new();
 
-        /* 0-63 64-127 128-191... */
-        let nines = NonZeroUsize::new(9999).unwrap();
-        for i in 0..64 {
-            assert_eq!(u64_index(i, nines), 0);
-        }
-        for i in 64..128 {
-            assert_eq!(u64_index(i, nines), 1);
-        }
-        for i in 128..192 {
-            assert_eq!(u64_index(i, nines), 2);
-        }
-        for i in 192..256 {
-            assert_eq!(u64_index(i, nines), 3);
-        }
 
-        /* 10 bits fit in one u64 */
-        for i in 0..9999 {
-            assert_eq!(u64_index(i, NonZeroUsize::new(10).unwrap()), 0);
-        }
+/// How much to say on stderr
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    /// Nothing, not even errors.  -s is both this and printing no results,
+    /// so scripts get only an exit code
+    Quiet,
+    /// Only errors
+    Normal,
+    /// What's being done (-v)
+    Verbose,
+    /// Hashes, bit positions and timings too (-vv)
+    Debug,
+}
 
-        /* 70 bits fit in two u64's only using 6 bits of the second one */
-        let seventy = NonZeroUsize::new(70).unwrap();
-        for i in 0..64 {
-            assert_eq!(u64_index(i, seventy), 0);
-        }
-        for i in 64..70 {
-            assert_eq!(u64_index(i, seventy), 1);
-        }
-        for i in 70..(70 + 64) {
-            assert_eq!(u64_index(i, seventy), 0);
-        }
-        for i in (70 + 64)..(70 + 64 + 6) {
-            assert_eq!(u64_index(i, seventy), 1);
-        }
 
-        /* 129 bits fit in three u64's only using 1 bit of the third one */
-        let one29 = NonZeroUsize::new(129).unwrap();
-        for i in 0..64 {
-            assert_eq!(u64_index(i, one29), 0);
-        }
-        for i in 64..128 {
-            assert_eq!(u64_index(i, one29), 1);
-        }
-        assert_eq!(u64_index(128, one29), 2);
-        for i in 129..(129 + 64) {
-            assert_eq!(u64_index(i, one29), 0);
-        }
-        for i in (129 + 64)..(129 + 2 * 64) {
-            assert_eq!(u64_index(i, one29), 1);
-        }
-        assert_eq!(u64_index(129 + 2 * 64, one29), 2);
-        assert_eq!(u64_index(129 + 2 * 64 + 1, one29), 0);
+/// Set once from -v and -s before anything is logged
+static LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+/// When main started, for timings
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+
+fn log_level() -> LogLevel {
+    *LOG_LEVEL.get().unwrap_or(&LogLevel::Normal)
+}
+
+
+fn log_level_from(verbose: u8, quiet: bool) -> LogLevel {
+    match (quiet, verbose) {
+        (true, _) => LogLevel::Quiet,
+        (false, 0) => LogLevel::Normal,
+        (false, 1) => LogLevel::Verbose,
+        (false, _) => LogLevel::Debug,
     }
+}
 
 
-    #[test]
-    fn test_bit_index() {
-        let nines = NonZeroUsize::new(9999).unwrap();
-        for i in 0..64 {
-            assert_eq!(bit_index(i, nines), i as u8);
-        }
-        for i in 64..128 {
-            assert_eq!(bit_index(i, nines), (i - 64) as u8);
-        }
-        for i in 128..192 {
-            assert_eq!(bit_index(i, nines), (i - 128) as u8);
-        }
+fn json_output() -> bool {
+    OUTPUT_FORMAT.get() == Some(&OutputFormat::Json)
+}
 
-        let ten = NonZeroUsize::new(10).unwrap();
-        for i in 0..9999 {
-            assert_eq!(bit_index(i, ten), (i % 10) as u8);
-        }
 
+/// Print `message` to stderr, as an error object with --format json, and
+/// exit with `code`
+fn fail(code: i32, message: impl Display) -> ! {
+    if log_level() == LogLevel::Quiet {
+        process::exit(code);
     }
+    if json_output() {
+        let message = message.to_string();
+        eprintln!("{}", json!({
+            "error": message.strip_prefix("ERROR: ").unwrap_or(&message),
+            "exit_code": code,
+        }));
+    }
+    else {
+        eprintln!("{}", message);
+    }
+    process::exit(code);
+}
 
-    #[test]
-    fn test_bit_array_indices() {
-        let nines = NonZeroUsize::new(9999).unwrap();
-        for i in 0..64 {
-            assert_eq!(bit_array_indices(i, nines), (0, i as u8));
-        }
-        for i in 64..128 {
-            assert_eq!(bit_array_indices(i, nines), (1, (i - 64) as u8));
-        }
-        for i in 128..192 {
-            assert_eq!(bit_array_indices(i, nines), (2, (i - 128) as u8));
-        }
-        for i in 0..300 {
-            assert_eq!(bit_array_indices(i, NonZeroUsize::new(10).unwrap()), (0, (i % 10) as u8));
-        }
+
+/// Print the outcome for one item with --format json.  `bits` are the
+/// positions in the filter the item hashes to, if they were asked for.
+fn print_json_result(filter_filename: &str, item_filename: &str, result: &str, bits: Option<Vec<usize>>) {
+    let mut object = json!({
+        "filter": filter_filename,
+        "path": item_filename,
+        "result": result,
+    });
+    if let Some(bits) = bits {
+        object["bits"] = Value::from(bits);
     }
+    println!("{}", object);
+}
 
 
-    #[test]
-    fn test_bit_set() {
-        for i in 0..63 {
-            assert_eq!(bit_set(0x00000000, i), Ok(false));
-        }
-        for i in 64..=0xff {
-            assert!(bit_set(0x00000000, i).is_err());
-        }
-        let deadbeef_bits = vec![0, 1, 3, 4, 5, 6, 8, 10, 12, 13, 15, 16, 18,
-                19, 20, 21, 22, 24, 25, 26, 28, 29, 30, 31];
-        for i in 0..0xff {
-            if deadbeef_bits.contains(&i) {
-                assert_eq!(bit_set(0xdeadbeef00000000, i), Ok(true));
-            }
-            else if i < 64 {
-                assert_eq!(bit_set(0xdeadbeef00000000, i), Ok(false));
-            }
-            else {
-                assert!(bit_set(0xdeadbeef00000000, i).is_err());
-            }
-        }
+/// The positions of the bits `bytes` hashes to in the filter at
+/// `filter_filename`, for --show-bits
+fn bits_or_fail(filter_filename: &str, bytes: &[u8], show_bits: bool) -> Option<Vec<usize>> {
+    if !show_bits {
+        return None;
+    }
+    let (_lock, file) = locked_filter_or_fail(filter_filename, false, true);
+    match LazyFilter::new(file) {
+        Ok(lazy) => Some(bit_numbers(bytes, lazy.header().m)),
+        Err(err) => {
+            fail(16, format!("ERROR: {:?}", err));
+        },
     }
+}
 
-    #[test]
-    fn test_filter() {
-
-        /* The bytes of the word "known" */
-        let known = "known".bytes().collect::<Vec<u8>>();
-
-        /* The 8 different hashes of the word "known" */
-        let known_hashes = vec![1183587150, 2402186983, 4132244288, 3394324783,
-                1291789908, 1182111577, 867046547, 3528127662,];
-        for hash_num in 0..8 {
-            let hash = xxh32::xxh32(&known, hash_num);
-            assert_eq!(hash, known_hashes[hash_num as usize]);
-        }
-
-        assert_eq!(num_u64s(M_NZ), FILTER_NUM_BITS);
-        let mut filter: [u64; FILTER_NUM_BITS] = [0; FILTER_NUM_BITS];
-
-        assert!(!is_in_filter(&known, &filter, M_NZ).unwrap());
-        assert!(filter_insert(&known, &mut filter, M_NZ).is_ok());
-        assert!(is_in_filter(&known, &filter, M_NZ).unwrap());
-
-        for i in 0..10000 {
-            let as_string = i.to_string();
-            let as_bytes = as_string.as_bytes();
-            assert!(!is_in_filter(&as_bytes, &filter, M_NZ).unwrap());
-            assert!(filter_insert(&as_bytes, &mut filter, M_NZ).is_ok());
-            assert!(is_in_filter(&as_bytes, &filter, M_NZ).unwrap());
-        }
-    }
-
-
-    #[test]
-    fn test_set_bit() {
-        assert_eq!(set_bit(0x0,  0), Ok(0x8000000000000000));
-        assert_eq!(set_bit(0x0,  1), Ok(0x4000000000000000));
-        assert_eq!(set_bit(0x0,  2), Ok(0x2000000000000000));
-        assert_eq!(set_bit(0x0,  3), Ok(0x1000000000000000));
-        assert_eq!(set_bit(0x0,  4), Ok(0x0800000000000000));
-        assert_eq!(set_bit(0x0,  5), Ok(0x0400000000000000));
-        assert_eq!(set_bit(0x0,  6), Ok(0x0200000000000000));
-        assert_eq!(set_bit(0x0,  7), Ok(0x0100000000000000));
-        assert_eq!(set_bit(0x0,  8), Ok(0x0080000000000000));
-        assert_eq!(set_bit(0x0,  9), Ok(0x0040000000000000));
-        assert_eq!(set_bit(0x0, 10), Ok(0x0020000000000000));
-        assert_eq!(set_bit(0x0, 11), Ok(0x0010000000000000));
-        assert_eq!(set_bit(0x0, 12), Ok(0x0008000000000000));
-        assert_eq!(set_bit(0x0, 13), Ok(0x0004000000000000));
-        assert_eq!(set_bit(0x0, 14), Ok(0x0002000000000000));
-        assert_eq!(set_bit(0x0, 15), Ok(0x0001000000000000));
-        assert_eq!(set_bit(0x0, 16), Ok(0x0000800000000000));
-        assert_eq!(set_bit(0x0, 17), Ok(0x0000400000000000));
-        assert_eq!(set_bit(0x0, 18), Ok(0x0000200000000000));
-        assert_eq!(set_bit(0x0, 19), Ok(0x0000100000000000));
-        assert_eq!(set_bit(0x0, 20), Ok(0x0000080000000000));
-        assert_eq!(set_bit(0x0, 21), Ok(0x0000040000000000));
-        assert_eq!(set_bit(0x0, 22), Ok(0x0000020000000000));
-        assert_eq!(set_bit(0x0, 23), Ok(0x0000010000000000));
-        assert_eq!(set_bit(0x0, 24), Ok(0x0000008000000000));
-        assert_eq!(set_bit(0x0, 25), Ok(0x0000004000000000));
-        assert_eq!(set_bit(0x0, 26), Ok(0x0000002000000000));
-        assert_eq!(set_bit(0x0, 27), Ok(0x0000001000000000));
-        assert_eq!(set_bit(0x0, 28), Ok(0x0000000800000000));
-        assert_eq!(set_bit(0x0, 29), Ok(0x0000000400000000));
-        assert_eq!(set_bit(0x0, 30), Ok(0x0000000200000000));
-        assert_eq!(set_bit(0x0, 31), Ok(0x0000000100000000));
-        assert_eq!(set_bit(0x0, 32), Ok(0x0000000080000000));
-        assert_eq!(set_bit(0x0, 33), Ok(0x0000000040000000));
-        assert_eq!(set_bit(0x0, 34), Ok(0x0000000020000000));
-        assert_eq!(set_bit(0x0, 35), Ok(0x0000000010000000));
-        assert_eq!(set_bit(0x0, 36), Ok(0x0000000008000000));
-        assert_eq!(set_bit(0x0, 37), Ok(0x0000000004000000));
-        assert_eq!(set_bit(0x0, 38), Ok(0x0000000002000000));
-        assert_eq!(set_bit(0x0, 39), Ok(0x0000000001000000));
-        assert_eq!(set_bit(0x0, 40), Ok(0x0000000000800000));
-        assert_eq!(set_bit(0x0, 41), Ok(0x0000000000400000));
-        assert_eq!(set_bit(0x0, 42), Ok(0x0000000000200000));
-        assert_eq!(set_bit(0x0, 43), Ok(0x0000000000100000));
-        assert_eq!(set_bit(0x0, 44), Ok(0x0000000000080000));
-        assert_eq!(set_bit(0x0, 45), Ok(0x0000000000040000));
-        assert_eq!(set_bit(0x0, 46), Ok(0x0000000000020000));
-        assert_eq!(set_bit(0x0, 47), Ok(0x0000000000010000));
-        assert_eq!(set_bit(0x0, 48), Ok(0x0000000000008000));
-        assert_eq!(set_bit(0x0, 49), Ok(0x0000000000004000));
-        assert_eq!(set_bit(0x0, 50), Ok(0x0000000000002000));
-        assert_eq!(set_bit(0x0, 51), Ok(0x0000000000001000));
-        assert_eq!(set_bit(0x0, 52), Ok(0x0000000000000800));
-        assert_eq!(set_bit(0x0, 53), Ok(0x0000000000000400));
-        assert_eq!(set_bit(0x0, 54), Ok(0x0000000000000200));
-        assert_eq!(set_bit(0x0, 55), Ok(0x0000000000000100));
-        assert_eq!(set_bit(0x0, 56), Ok(0x0000000000000080));
-        assert_eq!(set_bit(0x0, 57), Ok(0x0000000000000040));
-        assert_eq!(set_bit(0x0, 58), Ok(0x0000000000000020));
-        assert_eq!(set_bit(0x0, 59), Ok(0x0000000000000010));
-        assert_eq!(set_bit(0x0, 60), Ok(0x0000000000000008));
-        assert_eq!(set_bit(0x0, 61), Ok(0x0000000000000004));
-        assert_eq!(set_bit(0x0, 62), Ok(0x0000000000000002));
-        assert_eq!(set_bit(0x0, 63), Ok(0x0000000000000001));
-        for i in 64..=0xff {
-            assert!(set_bit(0x0, i).is_err());
-        }
-
-        /*
-        *   f     f  e b   f   f      f    f
-        *   !     !  ! !   !   !      !    !
-        * 1101 1110 1010 1101 1011 1110 1110 1111
-        * 0123 4567 8901 2345 6789 0123 4567 8901
-        *             11 1111 1111 2222 2222 2233
-        *   d    e    a    d    b    e    e    f
-        */
-        assert_eq!(set_bit(0xdeadbeef00000000,  0), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  1), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  2), Ok(0xfeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  3), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  4), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  5), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  6), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  7), Ok(0xdfadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  8), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000,  9), Ok(0xdeedbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 10), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 11), Ok(0xdebdbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 12), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 13), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 14), Ok(0xdeafbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 15), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 16), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 17), Ok(0xdeadfeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 18), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 19), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 20), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 21), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 22), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 23), Ok(0xdeadbfef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 24), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 25), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 26), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 27), Ok(0xdeadbeff00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 28), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 29), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 30), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 31), Ok(0xdeadbeef00000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 32), Ok(0xdeadbeef80000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 33), Ok(0xdeadbeef40000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 34), Ok(0xdeadbeef20000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 35), Ok(0xdeadbeef10000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 36), Ok(0xdeadbeef08000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 37), Ok(0xdeadbeef04000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 38), Ok(0xdeadbeef02000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 39), Ok(0xdeadbeef01000000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 40), Ok(0xdeadbeef00800000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 41), Ok(0xdeadbeef00400000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 42), Ok(0xdeadbeef00200000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 43), Ok(0xdeadbeef00100000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 44), Ok(0xdeadbeef00080000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 45), Ok(0xdeadbeef00040000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 46), Ok(0xdeadbeef00020000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 47), Ok(0xdeadbeef00010000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 48), Ok(0xdeadbeef00008000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 49), Ok(0xdeadbeef00004000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 50), Ok(0xdeadbeef00002000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 51), Ok(0xdeadbeef00001000));
-        assert_eq!(set_bit(0xdeadbeef00000000, 52), Ok(0xdeadbeef00000800));
-        assert_eq!(set_bit(0xdeadbeef00000000, 53), Ok(0xdeadbeef00000400));
-        assert_eq!(set_bit(0xdeadbeef00000000, 54), Ok(0xdeadbeef00000200));
-        assert_eq!(set_bit(0xdeadbeef00000000, 55), Ok(0xdeadbeef00000100));
-        assert_eq!(set_bit(0xdeadbeef00000000, 56), Ok(0xdeadbeef00000080));
-        assert_eq!(set_bit(0xdeadbeef00000000, 57), Ok(0xdeadbeef00000040));
-        assert_eq!(set_bit(0xdeadbeef00000000, 58), Ok(0xdeadbeef00000020));
-        assert_eq!(set_bit(0xdeadbeef00000000, 59), Ok(0xdeadbeef00000010));
-        assert_eq!(set_bit(0xdeadbeef00000000, 60), Ok(0xdeadbeef00000008));
-        assert_eq!(set_bit(0xdeadbeef00000000, 61), Ok(0xdeadbeef00000004));
-        assert_eq!(set_bit(0xdeadbeef00000000, 62), Ok(0xdeadbeef00000002));
-        assert_eq!(set_bit(0xdeadbeef00000000, 63), Ok(0xdeadbeef00000001));
-        for i in 64..=0xff {
-            assert!(set_bit(0xdeadbeef00000000, i).is_err());
-        }
+
+fn bit_numbers(bytes: &[u8], m: NonZeroUsize) -> Vec<usize> {
+    bit_positions(bytes, m).into_iter()
+        .map(|(whichint, whichbit)| whichint * 64 + whichbit as usize)
+        .collect()
+}
+
+
+/// At debug level, say what `bytes` from `item_filename` hashes to and, if
+/// the filter's header can be read, which of its bits that picks
+fn log_hashes(filter_filename: &str, item_filename: &str, bytes: &[u8]) {
+    if log_level() < LogLevel::Debug {
+        return;
+    }
+    log!(LogLevel::Debug, "'{}' hashes to {:08x?}", item_filename, item_hashes(bytes));
+    /* No lock needed, m never changes */
+    let header = File::open(filter_filename).ok().and_then(|file| LazyFilter::new(file).ok());
+    if let Some(lazy) = header {
+        log!(
+            LogLevel::Debug,
+            "picking bits {:?} of the {} in '{}'",
+            bit_numbers(bytes, lazy.header().m),
+            lazy.header().m,
+            filter_filename
+        );
     }
 }
 
 
-#[derive(Debug)]
-#[derive(FromArgs)]
-/// Elementary bloom filter
-struct Args {
-    /// an existing bloom filter's filename or that of one to be created.
-    /// `-x` is to emphasize that this can overwrite an existing file.
-    #[argh(option, short='x')]
-    filter_filename: String,
+/// At debug level, say how long things took since starting
+fn log_elapsed(what: &str) {
+    if let Some(started) = STARTED.get() {
+        log!(LogLevel::Debug, "{} after {:?}", what, started.elapsed());
+    }
+}
 
-    /// file to (i)nsert into the filter
-    #[argh(option, short='i')]
-    file_to_insert: Option<String>,
 
-    /// file to (q)uery for in the filter
-    #[argh(option, short='q')]
-    file_to_query: Option<String>,
+/// Something to insert or query for, and what to call it in output
+struct Item {
+    name: String,
+    bytes: Vec<u8>,
+}
 
-    /// send verbose output to stderr
-    #[argh(switch, short='v')]
-    verbose: bool,
+
+/// How the files given with -i and -q become items
+#[derive(Debug, Clone, Copy)]
+struct ItemSource {
+    view: View,
+    /// The files are lists of SHA-256 digests, each an item
+    digest_list: bool,
+    /// Each item is cut into content-defined chunks, each an item
+    chunks: bool,
+    /// The files are tar or zip archives, each of whose members is an item
+    archive: bool,
+    /// What the filter does to text items before they're hashed
+    normalize: Normalize,
 }
 
 
-fn fresh_filter() -> [u64; FILTER_NUM_BITS] {
-    [0; FILTER_NUM_BITS]
+/// The items in the file `filename`
+fn items_or_fail(filename: &str, source: ItemSource) -> Vec<Item> {
+    items(filename, source).unwrap_or_else(|(code, message)| {
+        fail(code, message);
+    })
 }
 
 
-fn bytes_or_fail(filename: &str) -> Vec<u8> {
-    match fs::read(&filename) {
-        Ok(actual_bytes) => {
-            actual_bytes
-        },
-        Err(err) => {
-            println!("Unable to read '{}' ({:?})", filename, err);
-            process::exit(6);
-        }
-    }
+/// The items in the file `filename`, or the exit code and message to fail
+/// with if they can't be had
+fn items(filename: &str, source: ItemSource) -> Result<Vec<Item>, (i32, String)> {
+    Ok(prepared_items(whole_items(filename, source)?, source))
 }
 
 
-fn regular_file_or_fail(filename: &str) {
-    let f_path = Path::new(&filename);
-    if !f_path.exists() {
-        println!("Cannot access file '{}'", filename);
-        process::exit(2);
+/// Whole `items` normalized and, if asked for, cut into chunks
+fn prepared_items(items: Vec<Item>, source: ItemSource) -> Vec<Item> {
+    let mut items = items;
+    for item in items.iter_mut() {
+        item.bytes = source.normalize.apply(std::mem::take(&mut item.bytes));
     }
-    if !f_path.is_file() {
-        println!("'{}' isn't a regular file", filename);
-        process::exit(3);
+    if !source.chunks {
+        return items;
     }
+    items.iter().flat_map(|item| {
+        chunk::chunks(&item.bytes).into_iter().map(|(offset, chunk)| Item {
+            name: format!("{}@{}", item.name, offset),
+            bytes: chunk.to_vec(),
+        })
+    }).collect()
 }
 
 
-/// Procedure that will exit whole program happily or with error
-fn query_existing_filter_and_quit(filter_filename: &str, query_filename: &str) {
-    if query_filename.eq(filter_filename) {
-        println!("Can't query for a filter in itself");
-        process::exit(5);
+fn whole_items(filename: &str, source: ItemSource) -> Result<Vec<Item>, (i32, String)> {
+    regular_file(filename)?;
+    if source.archive {
+        return archive_items(filename, source.view);
+    }
+    if !source.digest_list {
+        return match source.view.of_file(Path::new(filename)) {
+            Ok(bytes) => Ok(vec![Item {name: filename.to_owned(), bytes}]),
+            Err(err) => Err((6, format!("Unable to read '{}' ({})", filename, err))),
+        };
     }
 
-    regular_file_or_fail(query_filename);
-
-    let filter: Result<Vec<u64>, String> = read_filter_from_disk(&filter_filename).or_else(|err| {
-        println!("ERROR: {:?}", err);
-        process::exit(16);
-    });
-    let filter = filter.unwrap();
-
-    match is_in_filter(&bytes_or_fail(query_filename), &filter, M_NZ) {
-        Ok(is_in) => {
-            if is_in {
-                println!("IN");
-            }
-            else {
-                println!("NOT IN");
-            }
-            process::exit(0);
-        },
+    let list = match fs::read_to_string(filename) {
+        Ok(list) => list,
         Err(err) => {
-            println!("ERROR: {}", err);
-            process::exit(15);
+            return Err((6, format!("Unable to read '{}' ({})", filename, err)));
         },
+    };
+    match view::parse_digest_list(&list) {
+        Ok(digests) => Ok(digests.into_iter().map(|(name, bytes)| Item {name, bytes}).collect()),
+        Err(err) => Err((22, format!("'{}' isn't a digest list. {}", filename, err))),
     }
 }
 
 
-/// Procedure that will exit whole program happily or with error
-fn insert_existing_filter_and_quit(
-    verbosity: bool,
-    filter_filename: &str,
-    insert_filename: &str
-) {
-    if insert_filename.eq(filter_filename) {
-        println!("Can't add a filter to itself");
-        process::exit(5);
+/// Exit if the options for making items don't go together
+fn check_source_or_fail(source: &ItemSource) {
+    if source.archive && source.view != View::CONTENTS && source.view != View::SHA256 {
+        fail(USAGE_EXIT_CODE, "--archive only goes with --view contents or sha256");
     }
+    if !source.normalize.is_none() && (source.view != View::CONTENTS || source.digest_list) {
+        fail(USAGE_EXIT_CODE, "Normalizing text only goes with --view contents");
+    }
+}
 
-    regular_file_or_fail(insert_filename);
-
-    vprintln!(
-        verbosity,
-        "Adding file '{}' to existing filter at '{}'",
-        insert_filename,
-        filter_filename
-    );
 
-    match read_filter_from_disk(&filter_filename) {
-        Ok(mut filter) => {
-            let bytes_to_insert = bytes_or_fail(&insert_filename);
-            match filter_insert(&bytes_to_insert, &mut filter, M_NZ) {
-                Ok(()) => {
-                    match write_filter_to_disk(&filter_filename, &filter) {
-                        Ok(()) => {
-                            process::exit(0);
-                        },
-                        Err(err) => {
-                            println!("ERROR: {:?}", err);
-                            process::exit(16);
-                        }
-                    }
-                },
-                Err(err) => {
-                    println!("ERROR: {:?}", err);
-                    process::exit(7);
-                }
-            }
-        },
+/// The normalizers of the filter at `filter_filename`: those in its header
+/// if it exists, otherwise any asked for to make it with
+fn normalize_or_fail(filter_filename: &str, asked_for: Option<Normalize>, corrupt_code: i32) -> Normalize {
+    if !Path::new(filter_filename).exists() {
+        return asked_for.unwrap_or_default();
+    }
+    regular_file_or_fail(filter_filename);
+
+    /* No lock needed, a filter's normalizers never change.  One whose header
+     * can't be read exits `corrupt_code`, as it would when it's used, rather
+     * than going on as if it had no normalizers. */
+    let opened = File::open(filter_filename).map_err(|err| err.to_string()).and_then(LazyFilter::new);
+    let normalize = match opened {
+        Ok(lazy) => lazy.header().normalize,
         Err(err) => {
-            println!("ERROR: {:?}", err);
-            process::exit(5);
-        }
+            fail(corrupt_code, format!("ERROR: {:?}", err));
+        },
+    };
+    match asked_for {
+        Some(asked_for) if asked_for != normalize => {
+            fail(USAGE_EXIT_CODE, format!(
+                "'{}' already normalizes with {}, not {}",
+                filter_filename,
+                normalize,
+                asked_for
+            ));
+        },
+        _ => normalize,
     }
 }
 
 
-/// Procedure that will exit whole program happily or with error
-fn new_filter_and_quit(
-    verbosity: bool,
-    filter_filename: &str,
-    to_add_filename: Option<String>
-) {
-    let ff_path = Path::new(&filter_filename);
-    if ff_path.exists() {
-        println!("'{}' already exists", filter_filename);
-        process::exit(12);
-    }
-    vprintln!(verbosity, "Creating a new filter at '{}'", filter_filename);
-    let mut filter = fresh_filter();
-
-    let file_to_insert = match to_add_filename {
-        Some(ref filename) => {
-            regular_file_or_fail(&filename);
-            match fs::read(&filename) {
-                Ok(actual_bytes) => {
-                    Some(actual_bytes)
-                },
-                Err(err) => {
-                    println!("Unable to read '{:?}' ({:?})", to_add_filename.unwrap(), err);
-                    process::exit(6);
-                }
-            }
-        },
-        None => {
-            None
-        },
+/// The kind of filter --generations, --rotate-interval, --stable-decay,
+/// --stable-max and --ttl ask for
+fn asked_kind_or_fail(args: &Args) -> Kind {
+    let kinds = [
+        ("--generations", args.generations.is_some()),
+        ("--stable-decay", args.stable_decay.is_some()),
+        ("--ttl", args.ttl.is_some()),
+    ];
+    let given: Vec<&str> = kinds.iter().filter(|(_, given)| *given).map(|(option, _)| *option).collect();
+    if given.len() > 1 {
+        fail(USAGE_EXIT_CODE, format!("Cannot both {} and {}", given[0], given[1]));
+    }
+    if args.rotate_interval.is_some() && args.generations.is_none() {
+        fail(USAGE_EXIT_CODE, "--rotate-interval only goes with --generations");
+    }
+    if args.stable_max.is_some() && args.stable_decay.is_none() {
+        fail(USAGE_EXIT_CODE, "--stable-max only goes with --stable-decay");
+    }
+    let asked_for = if let Some(generations) = args.generations {
+        Rotation::new(generations, args.rotate_interval.unwrap_or(0), unix_now()).map(Kind::Rotating)
+    }
+    else if let Some(decrements) = args.stable_decay {
+        Stability::new(args.stable_max.unwrap_or(DEFAULT_CELL_MAX), decrements, 0, M_NZ).map(Kind::Stable)
+    }
+    else if let Some(ttl) = args.ttl {
+        Expiry::new(ttl, unix_now()).map(Kind::Expiring)
+    }
+    else {
+        Ok(Kind::Plain)
     };
+    asked_for.unwrap_or_else(|err| {
+        fail(USAGE_EXIT_CODE, err);
+    })
+}
 
-    if file_to_insert.is_some() {
-        if let Err(err) = filter_insert(&file_to_insert.unwrap(), &mut filter, M_NZ) {
-            println!("ERROR: {:?}", err);
-            process::exit(13);
-        }
-    }
 
-    match write_filter_to_disk(&filter_filename, &filter) {
-        Ok(()) => {
-            process::exit(0);
+/// The kind of filter `asked_for`, which the filter at `filter_filename`
+/// must already be if it exists
+fn kind_or_fail(filter_filename: &str, asked_for: Kind) -> Kind {
+    if asked_for == Kind::Plain {
+        return Kind::Plain;
+    }
+    if !Path::new(filter_filename).exists() {
+        return asked_for;
+    }
+    regular_file_or_fail(filter_filename);
+
+    /* As with normalizers, a filter's kind never changes */
+    let opened = File::open(filter_filename).map_err(|err| err.to_string()).and_then(LazyFilter::new);
+    match opened {
+        Ok(lazy) if !lazy.header().kind.matches(&asked_for) => {
+            fail(USAGE_EXIT_CODE, format!(
+                "'{}' is already {}, not {}",
+                filter_filename,
+                lazy.header().kind,
+                asked_for
+            ));
         },
-        Err(err) => {
-            println!(
-                "Couldn't write filter to disk at '{}': {:?}",
-                &filter_filename,
-                err
-            );
-            process::exit(10);
-        }
-    };
+        _ => asked_for,
+    }
 }
 
 
-fn main() {
-    let args: Args = argh::from_env();
+/// The members of the archive `filename`, named "<archive>:<member>"
+fn archive_items(filename: &str, view: View) -> Result<Vec<Item>, (i32, String)> {
+    let mut items = Vec::new();
+    let read = archive::for_each_member(Path::new(filename), |member, contents| items.push(Item {
+        name: format!("{}:{}", filename, member),
+        bytes: if view == View::SHA256 {view::sha256(&contents).to_vec()} else {contents},
+    }));
+    match read {
+        Ok(()) => Ok(items),
+        Err(err) => Err((23, format!("Unable to read archive '{}' ({})", filename, err))),
+    }
+}
 
-    let ff_path = Path::new(&args.filter_filename);
-    let create_new_filter = if ff_path.exists() {
-        regular_file_or_fail(&args.filter_filename);
-        false
+
+fn regular_file_or_fail(filename: &str) {
+    if let Err((code, message)) = regular_file(filename) {
+        fail(code, message);
     }
-    else {
-        true
-    };
+}
 
-    if args.file_to_insert.is_some() && args.file_to_query.is_some() {
-        println!(
-            "Cannot both insert and query for {}/{}",
-            args.file_to_insert.unwrap(),
-            args.file_to_query.unwrap()
-        );
-        process::exit(17);
+
+fn regular_file(filename: &str) -> Result<(), (i32, String)> {
+    let f_path = Path::new(&filename);
+    if !f_path.exists() {
+        return Err((2, format!("Cannot access file '{}'", filename)));
     }
+    if !f_path.is_file() {
+        return Err((3, format!("'{}' isn't a regular file", filename)));
+    }
+    Ok(())
+}
 
-    if create_new_filter {
-        if args.file_to_query.is_some() {
-            println!(
-                "Should not ask if '{}' is in an empty filter you're about to create at {}",
-                args.file_to_query.unwrap(),
-                args.filter_filename
-            );
-            process::exit(9);
+
+/// Take an advisory lock on an existing filter (see lock.rs), exclusive if
+/// it's going to be modified, shared otherwise, and then open it.  If `wait`
+/// is false and another process holds a conflicting lock, exit instead of
+/// blocking.
+fn locked_filter_or_fail(filter_filename: &str, exclusive: bool, wait: bool) -> (FilterLock, File) {
+    let filter_lock = match lock::lock(Path::new(filter_filename), exclusive, wait) {
+        Ok(filter_lock) => filter_lock,
+        Err(TryLockError::WouldBlock) => {
+            fail(18, format!("'{}' is locked by another process", filter_filename));
+        },
+        Err(TryLockError::Error(err)) => {
+            fail(16, format!("Unable to lock '{}' ({:?})", filter_filename, err));
+        },
+    };
+
+    match OpenOptions::new().read(true).write(exclusive).open(filter_filename) {
+        Ok(file) => (filter_lock, file),
+        Err(err) => {
+            fail(16, format!("ERROR: {:?}", err));
+        },
+    }
+}
+
+
+/// Where to find a server, from --socket or the environment
+fn socket_or_default(socket: Option<String>) -> Option<String> {
+    socket.or_else(|| env::var(SOCKET_ENV_VAR).ok().filter(|socket| !socket.is_empty()))
+}
+
+
+/// Have the server on `socket` (if any) handle `command` for `bytes` in the
+/// filter at `filter_filename`.  None means there's no server there or it
+/// isn't serving that filter, so the filter should be used directly.  A
+/// server that doesn't answer gives an ERROR response, since it may have
+/// acted on the request, so going to the filter too could be wrong.
+fn ask_server(
+    socket: Option<&str>,
+    command: &str,
+    bytes: &[u8],
+    filter_filename: &str
+) -> Option<String> {
+    let filter_path = fs::canonicalize(filter_filename).ok()?;
+    let request = format!(
+        "{} {} {}",
+        command,
+        server::format_hashes(&item_hashes(bytes)),
+        filter_path.display()
+    );
+    match server::ask(Path::new(socket?), &request) {
+        Ok(Some(response)) if response != "NOT SERVED" => Some(response),
+        Ok(_) => None,
+        Err(err) => Some(format!("ERROR {}", err)),
+    }
+}
+
+
+/// How to go about queries and report them
+struct QueryOptions<'a> {
+    source: ItemSource,
+    wait: bool,
+    use_mmap: bool,
+    /// Read only the u64s needed from a big filter, without checking the
+    /// checksum, if there's a single query
+    read_lazily: bool,
+    socket: Option<&'a str>,
+    show_bits: bool,
+    /// Print nothing and report through the exit status
+    quiet: bool,
+    /// With several queries, exit 0 if any rather than all are in
+    any: bool,
+}
+
+
+/// An existing filter opened for queries in whichever way suits it
+enum QueryableFilter {
+    Lazy {
+        lazy: LazyFilter,
+        /// Held as long as the filter's read from
+        _lock: FilterLock,
+    },
+    Mapped {
+        map: memmap2::Mmap,
+        /// Held as long as the filter's mapped
+        _lock: FilterLock,
+    },
+    Read(format::Header, Vec<u64>),
+}
+
+
+impl QueryableFilter {
+    fn open_or_fail(filter_filename: &str, options: &QueryOptions) -> QueryableFilter {
+        let (filter_lock, file) = locked_filter_or_fail(filter_filename, false, options.wait);
+        let filter_len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+        if options.read_lazily && filter_len >= LAZY_QUERY_MIN_LEN {
+            match LazyFilter::new(file) {
+                Ok(lazy) if lazy.header().kind == Kind::Plain => QueryableFilter::Lazy {lazy, _lock: filter_lock},
+                Ok(lazy) => QueryableFilter::read_or_fail(lazy.into_file()),
+                Err(err) => {
+                    fail(16, format!("ERROR: {:?}", err));
+                },
+            }
+        }
+        else if let Some(map) = mapped::map(&file).filter(|_| options.use_mmap) {
+            QueryableFilter::Mapped {map, _lock: filter_lock}
+        }
+        else {
+            QueryableFilter::read_or_fail(file)
+        }
+    }
+
+
+    fn read_or_fail(file: File) -> QueryableFilter {
+        match format::read_filter(&file) {
+            Ok((header, filter)) => QueryableFilter::Read(header, filter),
+            Err(err) => {
+                fail(16, format!("ERROR: {:?}", err));
+            },
+        }
+    }
+
+
+    fn contains_or_fail(&self, bytes: &[u8]) -> bool {
+        match self {
+            QueryableFilter::Lazy {lazy, ..} => lazy.contains(bytes).unwrap_or_else(|err| {
+                fail(16, format!("ERROR: {:?}", err));
+            }),
+            QueryableFilter::Mapped {map, ..} => mapped::query(map, bytes).unwrap_or_else(|err| {
+                fail(16, format!("ERROR: {:?}", err));
+            }),
+            QueryableFilter::Read(header, filter) => {
+                header.kind.contains(&item_hashes(bytes), filter, header.m, unix_now()).unwrap_or_else(|err| {
+                    fail(15, format!("ERROR: {}", err));
+                })
+            },
+        }
+    }
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn query_existing_filter_and_quit(
+    filter_filename: &str,
+    query_filenames: &[String],
+    options: &QueryOptions
+) {
+    if query_filenames.iter().any(|query_filename| query_filename.eq(filter_filename)) {
+        fail(5, "Can't query for a filter in itself");
+    }
+    if options.source.chunks {
+        query_chunks_and_quit(filter_filename, query_filenames, options);
+    }
+
+    let mut items = Vec::new();
+    for query_filename in query_filenames {
+        items.extend(items_or_fail(query_filename, options.source));
+    }
+    let options = &QueryOptions {read_lazily: options.read_lazily && items.len() == 1, ..*options};
+
+    let mut all_in = true;
+    let mut any_in = false;
+    let mut direct: Option<QueryableFilter> = None;
+
+    for item in items.iter() {
+        let is_in = is_in_or_fail(filter_filename, item, options, &mut direct);
+        all_in &= is_in;
+        any_in |= is_in;
+
+        if options.quiet {
+            continue;
+        }
+        let result = if is_in {"IN"} else {"NOT IN"};
+        if json_output() {
+            let bits = bits_or_fail(filter_filename, &item.bytes, options.show_bits);
+            print_json_result(filter_filename, &item.name, result, bits);
+        }
+        else if items.len() == 1 {
+            println!("{}", result);
+        }
+        else {
+            println!("{}: {}", item.name, result);
+        }
+    }
+
+    if options.quiet && !(if options.any {any_in} else {all_in}) {
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+
+/// Procedure that will exit whole program happily or with error, giving
+/// the fraction of each file's chunks that are (probably) in the filter
+fn query_chunks_and_quit(
+    filter_filename: &str,
+    query_filenames: &[String],
+    options: &QueryOptions
+) -> ! {
+    let options = &QueryOptions {read_lazily: false, ..*options};
+    let mut all_in = true;
+    let mut any_in = false;
+    let mut direct: Option<QueryableFilter> = None;
+
+    for query_filename in query_filenames {
+        let chunks = items_or_fail(query_filename, options.source);
+        let chunks_in = chunks.iter()
+            .filter(|chunk| is_in_or_fail(filter_filename, chunk, options, &mut direct))
+            .count();
+        let fraction = chunks_in as f64 / chunks.len() as f64;
+        all_in &= chunks_in == chunks.len();
+        any_in |= chunks_in == chunks.len();
+
+        if options.quiet {
+            continue;
+        }
+        if json_output() {
+            println!("{}", json!({
+                "filter": filter_filename,
+                "path": query_filename,
+                "chunks": chunks.len(),
+                "chunks_in": chunks_in,
+                "fraction": fraction,
+            }));
         }
-        let file_to_insert = if args.file_to_insert.is_some() {
-            Some(args.file_to_insert.unwrap())
+        else if query_filenames.len() == 1 {
+            println!("{:.3}", fraction);
         }
         else {
-            None
+            println!("{}: {:.3}", query_filename, fraction);
+        }
+    }
+
+    if options.quiet && !(if options.any {any_in} else {all_in}) {
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+
+/// Is `item` (probably) in the filter, asking the server if there is one
+/// and otherwise opening the filter into `direct` the first time
+fn is_in_or_fail(
+    filter_filename: &str,
+    item: &Item,
+    options: &QueryOptions,
+    direct: &mut Option<QueryableFilter>
+) -> bool {
+    log_hashes(filter_filename, &item.name, &item.bytes);
+    let looking = Instant::now();
+    let is_in = match ask_server(options.socket, "QUERY", &item.bytes, filter_filename) {
+        Some(response) if response == "IN" => true,
+        Some(response) if response == "NOT IN" => false,
+        Some(response) => {
+            fail(16, format!("ERROR: {:?}", response.trim_start_matches("ERROR ")));
+        },
+        None => {
+            direct.get_or_insert_with(|| {
+                QueryableFilter::open_or_fail(filter_filename, options)
+            }).contains_or_fail(&item.bytes)
+        },
+    };
+    log!(LogLevel::Debug, "Looked up '{}' in {:?}", item.name, looking.elapsed());
+    is_in
+}
+
+
+/// How to go about inserts and report them
+struct InsertOptions<'a> {
+    source: ItemSource,
+    /// What kind of filter to make if there isn't one yet
+    kind: Kind,
+    /// How long an expiring filter keeps the items, if not its own TTL
+    ttl: Option<u64>,
+    wait: bool,
+    use_mmap: bool,
+    socket: Option<&'a str>,
+    show_bits: bool,
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn insert_existing_filter_and_quit(
+    filter_filename: &str,
+    insert_filename: &str,
+    options: &InsertOptions
+) {
+    if insert_filename.eq(filter_filename) {
+        fail(5, "Can't add a filter to itself");
+    }
+
+    let items = items_or_fail(insert_filename, options.source);
+
+    log!(
+        LogLevel::Verbose,
+        "Adding file '{}' to existing filter at '{}'",
+        insert_filename,
+        filter_filename
+    );
+    insert_items_or_fail(filter_filename, &items, options);
+
+    log_elapsed("Inserted");
+    for item in items.iter() {
+        report_inserted(filter_filename, item, options.show_bits);
+    }
+    process::exit(0);
+}
+
+
+/// Insert `items` through the server if it's serving the filter, and
+/// directly otherwise, all under one lock
+fn insert_items_or_fail(filter_filename: &str, items: &[Item], options: &InsertOptions) {
+    let mut direct = Vec::new();
+    for item in items.iter() {
+        match ask_server(options.socket, "INSERT", &item.bytes, filter_filename) {
+            Some(response) if response == "OK" => {
+                log!(
+                    LogLevel::Verbose,
+                    "Inserted '{}' by the server at '{}'",
+                    item.name,
+                    options.socket.unwrap_or_default()
+                );
+            },
+            Some(response) => {
+                fail(7, format!("ERROR: {:?}", response.trim_start_matches("ERROR ")));
+            },
+            None => direct.push(item),
+        }
+    }
+
+    if !direct.is_empty() {
+        /* Hold the lock across the whole read-modify-write so concurrent
+         * inserters can't overwrite each other's bits */
+        let (_lock, file) = locked_filter_or_fail(filter_filename, true, options.wait);
+
+        if let Some(mut map) = mapped::map_mut(&file).filter(|_| options.use_mmap) {
+            if let Err(err) = mapped::insert_all(&mut map, direct.iter().map(|item| &item.bytes[..])) {
+                fail(5, format!("ERROR: {:?}", err));
+            }
+        }
+        else {
+            let (mut header, mut filter) = format::read_filter(&file).unwrap_or_else(|err| {
+                fail(5, format!("ERROR: {:?}", err));
+            });
+            let now = unix_now();
+            for item in direct {
+                let hashes = item_hashes(&item.bytes);
+                let inserted = header.kind.test_and_insert(&hashes, &mut filter, header.m, now, options.ttl);
+                if let Err(err) = inserted {
+                    fail(7, format!("ERROR: {:?}", err));
+                }
+            }
+            if let Err(err) = format::replace_filter(Path::new(filter_filename), &header, &filter) {
+                fail(16, format!("ERROR: {:?}", err));
+            }
+        }
+    }
+}
+
+
+/// Procedure that will exit whole program with 0 if everything was added, 1
+/// if anything was already in or with error
+fn add_if_absent_and_quit(
+    filter_filename: &str,
+    insert_filename: &str,
+    options: &InsertOptions,
+    quiet: bool
+) {
+    if insert_filename.eq(filter_filename) {
+        fail(5, "Can't add a filter to itself");
+    }
+    let items = items_or_fail(insert_filename, options.source);
+    create_filter_if_missing_or_fail(filter_filename, options.source.normalize, options.kind);
+
+    let mut were_in = Vec::new();
+    let mut direct = Vec::new();
+    for item in items.iter() {
+        log_hashes(filter_filename, &item.name, &item.bytes);
+        were_in.push(match ask_server(options.socket, "ADD", &item.bytes, filter_filename) {
+            Some(response) if response == "ADDED" => Some(false),
+            Some(response) if response == "ALREADY IN" => Some(true),
+            Some(response) => {
+                fail(7, format!("ERROR: {:?}", response.trim_start_matches("ERROR ")));
+            },
+            None => {
+                direct.push(item);
+                None
+            },
+        });
+    }
+    let mut direct_were_in = test_and_insert_directly_or_fail(filter_filename, &direct, options).into_iter();
+
+    let mut all_added = true;
+    for (item, was_in) in items.iter().zip(were_in) {
+        let was_in = was_in.or_else(|| direct_were_in.next()).unwrap_or_default();
+        all_added &= !was_in;
+
+        let result = if was_in {"ALREADY IN"} else {"ADDED"};
+        if quiet {
+            continue;
+        }
+        if json_output() {
+            print_json_result(filter_filename, &item.name, result, None);
+        }
+        else if items.len() == 1 {
+            println!("{}", result);
+        }
+        else {
+            println!("{}: {}", item.name, result);
+        }
+    }
+
+    log_elapsed("Added");
+    process::exit(if all_added {0} else {1});
+}
+
+
+/// Make an empty filter at `filter_filename` unless there's one there,
+/// which is fine even if it's only just been made by someone else
+fn create_filter_if_missing_or_fail(filter_filename: &str, normalize: Normalize, kind: Kind) {
+    if !Path::new(filter_filename).exists() {
+        log!(LogLevel::Verbose, "Creating a new filter at '{}'", filter_filename);
+        let filter = vec![0; kind.body_u64s(M_NZ).unwrap_or_else(|err| {
+            fail(13, format!("ERROR: {:?}", err));
+        })];
+        write_new_filter_or_fail(filter_filename, normalize, kind, &filter);
+    }
+    regular_file_or_fail(filter_filename);
+}
+
+
+/// Write a new filter to `filter_filename` all at once, returning false if
+/// there's already one there.  It's written to a temporary file beside it
+/// that's then linked into place, so nobody can open it half written.
+fn write_new_filter_or_fail(filter_filename: &str, normalize: Normalize, kind: Kind, filter: &[u64]) -> bool {
+    let path = Path::new(filter_filename);
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        process::id()
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            format::write_kind_filter(&file, M_NZ, normalize, kind, filter)?;
+            file.sync_all().map_err(|err| err.to_string())
+        })
+        .and_then(|()| match fs::hard_link(&temp_path, path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err.to_string()),
+        });
+    let _ = fs::remove_file(&temp_path);
+    written.unwrap_or_else(|err| {
+        fail(10, format!("Couldn't write filter to disk at '{}': {:?}", filter_filename, err));
+    })
+}
+
+
+/// Insert `items` into the filter at `filter_filename` under one lock,
+/// returning whether each was already in, counting those before it
+fn test_and_insert_directly_or_fail(filter_filename: &str, items: &[&Item], options: &InsertOptions) -> Vec<bool> {
+    if items.is_empty() {
+        return Vec::new();
+    }
+    let (_lock, file) = locked_filter_or_fail(filter_filename, true, options.wait);
+    if let Some(mut map) = mapped::map_mut(&file).filter(|_| options.use_mmap) {
+        return mapped::test_and_insert_all(&mut map, items.iter().map(|item| &item.bytes[..]))
+            .unwrap_or_else(|err| {
+                fail(5, format!("ERROR: {:?}", err));
+            });
+    }
+
+    let (mut header, mut filter) = format::read_filter(&file).unwrap_or_else(|err| {
+        fail(5, format!("ERROR: {:?}", err));
+    });
+    let now = unix_now();
+    let were_in: Vec<bool> = items.iter().map(|item| {
+        let hashes = item_hashes(&item.bytes);
+        header.kind.test_and_insert(&hashes, &mut filter, header.m, now, options.ttl).unwrap_or_else(|err| {
+            fail(7, format!("ERROR: {:?}", err));
+        })
+    }).collect();
+    /* Other kinds change even when the items were in */
+    if were_in.contains(&false) || header.kind != Kind::Plain {
+        if let Err(err) = format::replace_filter(Path::new(filter_filename), &header, &filter) {
+            fail(16, format!("ERROR: {:?}", err));
+        }
+    }
+    were_in
+}
+
+
+/// Say `item` was inserted, with --format json
+fn report_inserted(filter_filename: &str, item: &Item, show_bits: bool) {
+    log_hashes(filter_filename, &item.name, &item.bytes);
+    if json_output() {
+        let bits = bits_or_fail(filter_filename, &item.bytes, show_bits);
+        print_json_result(filter_filename, &item.name, "INSERTED", bits);
+    }
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn new_filter_and_quit(
+    filter_filename: &str,
+    to_add_filename: Option<String>,
+    source: ItemSource,
+    kind: Kind,
+    show_bits: bool
+) {
+    let ff_path = Path::new(&filter_filename);
+    if ff_path.exists() {
+        fail(12, format!("'{}' already exists", filter_filename));
+    }
+    log!(LogLevel::Verbose, "Creating a new filter at '{}'", filter_filename);
+    let mut kind = kind;
+    let mut filter = vec![0; kind.body_u64s(M_NZ).unwrap_or_else(|err| {
+        fail(13, format!("ERROR: {:?}", err));
+    })];
+
+    let items = match to_add_filename {
+        Some(ref filename) => items_or_fail(filename, source),
+        None => Vec::new(),
+    };
+
+    let now = unix_now();
+    for item in items.iter() {
+        if let Err(err) = kind.test_and_insert(&item_hashes(&item.bytes), &mut filter, M_NZ, now, None) {
+            fail(13, format!("ERROR: {:?}", err));
+        }
+    }
+
+    /* Linked into place so that a filter created by someone else since the
+     * check above isn't clobbered, and nobody sees this one half written */
+    if !write_new_filter_or_fail(filter_filename, source.normalize, kind, &filter) {
+        fail(12, format!("'{}' already exists", filter_filename));
+    }
+    log_elapsed("Created");
+    for item in items.iter() {
+        report_inserted(filter_filename, item, show_bits);
+    }
+    process::exit(0);
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn verify_filter_and_quit(filter_filename: &str) {
+    regular_file_or_fail(filter_filename);
+
+    let (_lock, mut file) = locked_filter_or_fail(filter_filename, false, true);
+    let mut bytes = Vec::<u8>::new();
+    if let Err(err) = file.read_to_end(&mut bytes) {
+        fail(16, format!("ERROR: {:?}", err));
+    }
+
+    match format::verify_filter(&bytes) {
+        Ok(header) if json_output() => {
+            let mut object = json!({
+                "filter": filter_filename,
+                "result": "OK",
+                "version": header.version,
+                "m": usize::from(header.m),
+                "k": header.num_hashes,
+                "checksum": header.checksum.map(|checksum| format!("{:016x}", checksum)),
+            });
+            if !header.normalize.is_none() {
+                object["normalize"] = Value::from(header.normalize.to_string());
+            }
+            if let Kind::Rotating(rotation) = header.kind {
+                object["kind"] = Value::from(header.kind.name());
+                object["generations"] = Value::from(rotation.generations);
+                object["rotate_interval"] = Value::from(rotation.interval);
+                object["rotated_at"] = Value::from(rotation.rotated_at);
+            }
+            if let Kind::Stable(stability) = header.kind {
+                object["kind"] = Value::from(header.kind.name());
+                object["stable_max"] = Value::from(stability.max);
+                object["stable_decay"] = Value::from(stability.decrements);
+            }
+            if let Kind::Expiring(expiry) = header.kind {
+                object["kind"] = Value::from(header.kind.name());
+                object["ttl"] = Value::from(expiry.ttl);
+                object["epoch"] = Value::from(expiry.epoch);
+            }
+            println!("{}", object);
+            process::exit(0);
+        },
+        Ok(header) => {
+            match header.checksum {
+                Some(checksum) => {
+                    let mut settings = String::new();
+                    if !header.normalize.is_none() {
+                        settings.push_str(&format!(", normalizing {}", header.normalize));
+                    }
+                    if header.kind != Kind::Plain {
+                        settings.push_str(&format!(", {}", header.kind));
+                    }
+                    println!(
+                        "OK (version {}, m = {}, k = {}, checksum {:016x}{})",
+                        header.version,
+                        header.m,
+                        header.num_hashes,
+                        checksum,
+                        settings
+                    );
+                },
+                None => {
+                    println!(
+                        "OK (legacy format without a checksum, m = {}, k = {})",
+                        header.m,
+                        header.num_hashes
+                    );
+                },
+            }
+            process::exit(0);
+        },
+        Err(err) => {
+            fail(19, format!("CORRUPT: {}", err));
+        },
+    }
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn rotate_and_quit(filter_filename: &str, wait: bool) {
+    regular_file_or_fail(filter_filename);
+    let (_lock, file) = locked_filter_or_fail(filter_filename, true, wait);
+    let (mut header, mut filter) = format::read_filter(&file).unwrap_or_else(|err| {
+        fail(5, format!("ERROR: {:?}", err));
+    });
+    match &mut header.kind {
+        Kind::Rotating(rotation) => rotation.rotate(&mut filter, header.m, unix_now()),
+        _ => {
+            fail(USAGE_EXIT_CODE, format!("'{}' isn't a rotating filter", filter_filename));
+        },
+    }
+    if let Err(err) = format::replace_filter(Path::new(filter_filename), &header, &filter) {
+        fail(16, format!("ERROR: {:?}", err));
+    }
+    log!(LogLevel::Verbose, "Rotated '{}'", filter_filename);
+    process::exit(0);
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn compact_and_quit(filter_filename: &str, wait: bool) {
+    regular_file_or_fail(filter_filename);
+    let (_lock, file) = locked_filter_or_fail(filter_filename, true, wait);
+    let (header, mut filter) = format::read_filter(&file).unwrap_or_else(|err| {
+        fail(5, format!("ERROR: {:?}", err));
+    });
+    let cleared = match header.kind {
+        Kind::Expiring(expiry) => expiry.compact(&mut filter, header.m, unix_now()).unwrap_or_else(|err| {
+            fail(5, format!("ERROR: {:?}", err));
+        }),
+        _ => {
+            fail(USAGE_EXIT_CODE, format!("'{}' isn't an expiring filter", filter_filename));
+        },
+    };
+    if cleared > 0 {
+        if let Err(err) = format::replace_filter(Path::new(filter_filename), &header, &filter) {
+            fail(16, format!("ERROR: {:?}", err));
+        }
+    }
+    log!(LogLevel::Verbose, "Cleared {} expired cells from '{}'", cleared, filter_filename);
+    process::exit(0);
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn dupes_and_quit(dupes_args: DupesArgs) {
+    let dir = Path::new(&dupes_args.dir);
+    if !dir.is_dir() {
+        fail(2, format!("Cannot access directory '{}'", dupes_args.dir));
+    }
+    let mut skipped = Vec::new();
+    let files = dupes::files_under(dir, &mut skipped).unwrap_or_else(|err| {
+        fail(6, err);
+    });
+    for message in skipped {
+        log!(LogLevel::Normal, "Skipping: {}", message);
+    }
+    log!(LogLevel::Verbose, "Looking for duplicates among {} files", files.len());
+
+    let quiet = log_level() == LogLevel::Quiet;
+    let mut any_found = false;
+    let mut finder = DupeFinder::for_files(files.len(), dupes_args.confirm);
+    for file in files.iter() {
+        let found = match finder.check(file) {
+            Ok(found) => found,
+            Err(err) => {
+                log!(LogLevel::Normal, "Skipping: {}", err);
+                continue;
+            },
         };
-        new_filter_and_quit(
-            args.verbose,
-            &args.filter_filename,
-            file_to_insert
-        );
+        let path = file.display().to_string();
+        match found {
+            Found::New => {},
+            Found::FalsePositive => {
+                log!(LogLevel::Verbose, "'{}' looked like a duplicate but isn't", path);
+            },
+            _ if quiet => any_found = true,
+            Found::Candidate if json_output() => {
+                println!("{}", json!({"path": path, "result": "CANDIDATE"}));
+            },
+            Found::Candidate => println!("{}", path),
+            Found::Duplicate(original) if json_output() => {
+                println!("{}", json!({
+                    "path": path,
+                    "result": "DUPLICATE",
+                    "duplicate_of": original.display().to_string(),
+                }));
+            },
+            Found::Duplicate(original) => println!("{}: {}", path, original.display()),
+        }
     }
-    else {
-        if args.file_to_insert.is_some() {
-            insert_existing_filter_and_quit(
-                args.verbose,
-                &args.filter_filename,
-                &args.file_to_insert.unwrap()
-            );
+
+    log_elapsed("Looked");
+    process::exit(if quiet && !any_found {1} else {0});
+}
+
+
+/// Procedure that will insert files until killed or exit with error
+fn watch_and_quit(watch_args: WatchArgs, options: &InsertOptions) {
+    let dir = Path::new(&watch_args.dir);
+    if !dir.is_dir() {
+        fail(2, format!("Cannot access directory '{}'", watch_args.dir));
+    }
+    if watch_args.batch_interval == 0 {
+        fail(USAGE_EXIT_CODE, "--batch-interval must be at least 1 second");
+    }
+    let filter_filename = &watch_args.filter_filename;
+    create_filter_if_missing_or_fail(filter_filename, options.source.normalize, options.kind);
+    /* Don't go inserting the filter into itself if it's under dir */
+    let filter_path = fs::canonicalize(filter_filename).unwrap_or_default();
+
+    let mut watcher = TreeWatcher::new(dir).unwrap_or_else(|err| {
+        fail(24, format!("ERROR: {}", err));
+    });
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).unwrap_or_else(|err| {
+        fail(24, format!("ERROR: Unable to handle signals ({})", err));
+    });
+    log!(LogLevel::Verbose, "Watching '{}' for files to insert into '{}'", watch_args.dir, filter_filename);
+
+    /* Both block, so each gets a thread to wait in and sends what it got
+    *  here, None meaning it's time to stop */
+    let (sender, receiver) = mpsc::channel();
+    let watch_sender = sender.clone();
+    thread::spawn(move || loop {
+        let ready = watcher.wait();
+        let failed = ready.is_err();
+        if watch_sender.send(Some(ready)).is_err() || failed {
+            return;
         }
-        else if args.file_to_query.is_some() {
-            query_existing_filter_and_quit(
-                &args.filter_filename,
-                &args.file_to_query.unwrap()
-            );
+    });
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            let _ = sender.send(None);
+        }
+    });
+
+    let batch_interval = Duration::from_secs(watch_args.batch_interval);
+    let mut pending: Vec<PathBuf> = Vec::new();
+    let mut batch_started = Instant::now();
+    loop {
+        let received = if pending.is_empty() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        }
+        else {
+            receiver.recv_timeout(batch_interval.saturating_sub(batch_started.elapsed()))
+        };
+        /* Insert whatever's gathered before going */
+        let stopping = match received {
+            Ok(Some(Ok(ready))) => {
+                if pending.is_empty() {
+                    batch_started = Instant::now();
+                }
+                pending.extend(ready.into_iter().filter(|path| {
+                    fs::canonicalize(path).map_or(true, |path| path != filter_path)
+                }));
+                false
+            },
+            Ok(Some(Err(err))) => {
+                fail(24, format!("ERROR: {}", err));
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if !pending.is_empty() && (stopping || batch_started.elapsed() >= batch_interval) {
+            pending.sort();
+            pending.dedup();
+            insert_batch_or_fail(filter_filename, &pending, options);
+            pending.clear();
+        }
+        if stopping {
+            log!(LogLevel::Verbose, "Stopped watching '{}'", watch_args.dir);
+            process::exit(0);
+        }
+    }
+}
+
+
+/// Insert the items in all of `filenames` at once, skipping any that can't
+/// be read (they may be gone again already)
+fn insert_batch_or_fail(filter_filename: &str, filenames: &[PathBuf], options: &InsertOptions) {
+    let mut batch = Vec::new();
+    for filename in filenames {
+        match items(&filename.to_string_lossy(), options.source) {
+            Ok(file_items) => batch.extend(file_items),
+            Err((_, message)) => log!(LogLevel::Normal, "Skipping: {}", message),
+        }
+    }
+    insert_items_or_fail(filter_filename, &batch, options);
+    log!(
+        LogLevel::Verbose,
+        "Inserted {} items from {} files into '{}'",
+        batch.len(),
+        filenames.len(),
+        filter_filename
+    );
+    for item in batch.iter() {
+        report_inserted(filter_filename, item, options.show_bits);
+    }
+}
+
+
+/// Procedure that will exit whole program happily or with error
+fn git_index_and_quit(git_args: GitIndexArgs, options: &InsertOptions) {
+    let view = options.source.view;
+    if options.source.digest_list || options.source.archive {
+        fail(USAGE_EXIT_CODE, "git-index doesn't go with --digest-list or --archive");
+    }
+    if view != View::CONTENTS && view != View::SHA256 && view != View::GIT_OID {
+        fail(USAGE_EXIT_CODE, "git-index only goes with --view contents, sha256 or git-oid");
+    }
+    let repo = GitRepo::open(Path::new(&git_args.repo)).unwrap_or_else(|err| {
+        fail(25, format!("Unable to read git repository '{}' ({})", git_args.repo, err));
+    });
+    let ids = repo.blob_ids().unwrap_or_else(|err| {
+        fail(25, format!("Unable to read git repository '{}' ({})", git_args.repo, err));
+    });
+    let filter_filename = &git_args.filter_filename;
+    create_filter_if_missing_or_fail(filter_filename, options.source.normalize, options.kind);
+    log!(LogLevel::Verbose, "Adding {} blobs from '{}' to '{}'", ids.len(), git_args.repo, filter_filename);
+
+    /* A repository's whole history needn't fit in memory at once */
+    for batch_ids in ids.chunks(GIT_BATCH_LEN) {
+        let mut batch = Vec::new();
+        for id in batch_ids {
+            let bytes = if view == View::GIT_OID {
+                id.as_bytes().to_vec()
+            }
+            else {
+                let contents = repo.blob(*id).unwrap_or_else(|err| {
+                    fail(25, format!("Unable to read git repository '{}' ({})", git_args.repo, err));
+                });
+                if view == View::SHA256 {view::sha256(&contents).to_vec()} else {contents}
+            };
+            batch.push(Item {name: format!("{}:{}", git_args.repo, id), bytes});
+        }
+        let batch = prepared_items(batch, options.source);
+        insert_items_or_fail(filter_filename, &batch, options);
+        for item in batch.iter() {
+            report_inserted(filter_filename, item, options.show_bits);
+        }
+    }
+
+    log_elapsed("Inserted");
+    process::exit(0);
+}
+
+
+/// Procedure that will serve until killed or exit with error
+fn serve_and_quit(serve_args: ServeArgs) {
+    if serve_args.filter_filename.is_empty() {
+        fail(USAGE_EXIT_CODE, "At least one filter must be given with -x");
+    }
+    for filter_filename in serve_args.filter_filename.iter() {
+        regular_file_or_fail(filter_filename);
+    }
+    if serve_args.flush_interval == 0 {
+        fail(USAGE_EXIT_CODE, "--flush-interval must be at least 1 second");
+    }
+
+    let mut filters = match server::FilterSet::load(&serve_args.filter_filename) {
+        Ok(filters) => filters,
+        Err(err) => {
+            fail(16, format!("ERROR: {}", err));
+        },
+    };
+    if let Some(wal) = serve_args.wal.as_ref() {
+        match filters.log_to(Path::new(wal)) {
+            Ok(replayed) => {
+                log!(LogLevel::Verbose, "Replayed {} inserts from '{}'", replayed, wal);
+            },
+            Err(err) => {
+                fail(16, format!("ERROR: {}", err));
+            },
+        }
+    }
+
+    let socket = socket_or_default(serve_args.socket);
+    if socket.is_none() && serve_args.http.is_none() && serve_args.resp.is_none() {
+        fail(USAGE_EXIT_CODE, format!(
+            "Nothing to listen on, give --socket (or ${}), --http and/or --resp",
+            SOCKET_ENV_VAR
+        ));
+    }
+
+    /* Before there's anything to serve, so a signal can't kill us outright
+    *  with inserts yet to be flushed */
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).unwrap_or_else(|err| {
+        fail(20, format!("ERROR: Unable to handle signals ({})", err));
+    });
+
+    let listener = socket.as_ref().map(|socket| {
+        server::bind(Path::new(socket)).unwrap_or_else(|err| {
+            fail(20, format!("ERROR: {}", err));
+        })
+    });
+    let http_server = serve_args.http.as_ref().map(|address| {
+        http::bind(address).unwrap_or_else(|err| {
+            fail(20, format!("ERROR: {}", err));
+        })
+    });
+    let resp_listener = serve_args.resp.as_ref().map(|address| {
+        resp::bind(address).unwrap_or_else(|err| {
+            fail(20, format!("ERROR: {}", err));
+        })
+    });
+
+    for path in filters.paths() {
+        log!(LogLevel::Verbose, "Serving '{}'", path.display());
+    }
+    let filters = Arc::new(filters);
+    server::flush_every(
+        Arc::clone(&filters),
+        Duration::from_secs(serve_args.flush_interval)
+    );
+
+    if let Some(listener) = listener {
+        log!(LogLevel::Verbose, "Listening on '{}'", socket.as_deref().unwrap_or_default());
+        let filters = Arc::clone(&filters);
+        thread::spawn(move || server::serve(listener, filters));
+    }
+    if let Some(http_server) = http_server {
+        log!(LogLevel::Verbose, "Listening for HTTP on '{}'", http_server.server_addr());
+        let filters = Arc::clone(&filters);
+        thread::spawn(move || http::serve(http_server, filters));
+    }
+    if let Some(resp_listener) = resp_listener {
+        if let Ok(address) = resp_listener.local_addr() {
+            log!(LogLevel::Verbose, "Listening for RESP on '{}'", address);
+        }
+        let filters = Arc::clone(&filters);
+        thread::spawn(move || resp::serve(resp_listener, filters));
+    }
+
+    /* Serve until told to stop, then write everything out before going */
+    if let Some(signal) = signals.forever().next() {
+        log!(LogLevel::Verbose, "Got signal {}, flushing and shutting down", signal);
+    }
+    filters.close();
+    if let Some(socket) = socket {
+        let _ = fs::remove_file(socket);
+    }
+    if let Err(err) = filters.flush() {
+        fail(16, format!("ERROR: {}", err));
+    }
+    process::exit(0);
+}
+
+
+/// Like `argh::from_env`, but exiting with USAGE_EXIT_CODE on bad arguments
+fn args_or_fail() -> Args {
+    let mut strings: Vec<String> = Vec::new();
+    let mut flags_done = false;
+    for arg in env::args_os() {
+        let arg = arg.to_string_lossy().into_owned();
+        /* argh doesn't take -vv for -v -v, but leave option values (say a
+        *  file named -vv) and everything after -- alone */
+        let is_value = strings.last().is_some_and(|last| VALUED_OPTIONS.contains(&last.as_str()));
+        flags_done |= arg == "--" && !is_value;
+        if !flags_done && !is_value && arg.len() > 2 && arg.starts_with('-')
+            && arg[1..].bytes().all(|byte| byte == b'v') {
+            strings.extend((1..arg.len()).map(|_| "-v".to_owned()));
         }
         else {
-            println!("Nothing to do with an existing filter if no file given to query or insert.");
-            process::exit(14);
+            strings.push(arg);
+        }
+    }
+    let command = strings.first().map_or("bloom-cli", |command| {
+        Path::new(command).file_name().and_then(|name| name.to_str()).unwrap_or(command)
+    });
+    let strs: Vec<&str> = strings.iter().skip(1).map(|string| string.as_str()).collect();
+
+    Args::from_args(&[command], &strs).unwrap_or_else(|early_exit| {
+        match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                process::exit(0);
+            },
+            Err(()) => {
+                eprintln!("{}\nRun {} --help for more information.", early_exit.output, command);
+                process::exit(USAGE_EXIT_CODE);
+            },
         }
+    })
+}
+
+
+fn main() {
+    STARTED.get_or_init(Instant::now);
+    let args = args_or_fail();
+    OUTPUT_FORMAT.get_or_init(|| args.format);
+    LOG_LEVEL.get_or_init(|| match &args.command {
+        Some(Command::Serve(serve_args)) => log_level_from(serve_args.verbose.max(args.verbose), false),
+        Some(Command::AddIfAbsent(add_args)) => log_level_from(args.verbose, args.quiet || add_args.quiet),
+        _ => log_level_from(args.verbose, args.quiet),
+    });
+
+    let mut source = ItemSource {
+        view: if args.digest_list {View::SHA256} else {args.view},
+        digest_list: args.digest_list,
+        chunks: args.chunks,
+        archive: args.archive,
+        normalize: Normalize::NONE,
+    };
+    if args.archive && args.digest_list {
+        fail(USAGE_EXIT_CODE, "Cannot both --archive and --digest-list");
+    }
+    let asked_kind = asked_kind_or_fail(&args);
+
+    match args.command {
+        Some(Command::Verify(verify_args)) => {
+            verify_filter_and_quit(&verify_args.filter_filename);
+        },
+        Some(Command::Serve(serve_args)) => {
+            serve_and_quit(serve_args);
+        },
+        Some(Command::Dupes(dupes_args)) => {
+            dupes_and_quit(dupes_args);
+        },
+        Some(Command::Rotate(rotate_args)) => {
+            if args.wait && args.no_wait {
+                fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+            }
+            rotate_and_quit(&rotate_args.filter_filename, !args.no_wait);
+        },
+        Some(Command::Compact(compact_args)) => {
+            if args.wait && args.no_wait {
+                fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+            }
+            compact_and_quit(&compact_args.filter_filename, !args.no_wait);
+        },
+        Some(Command::Watch(watch_args)) => {
+            if args.wait && args.no_wait {
+                fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+            }
+            let socket = socket_or_default(args.socket.clone());
+            source.normalize = normalize_or_fail(&watch_args.filter_filename, args.normalize, 5);
+            check_source_or_fail(&source);
+            let options = InsertOptions {
+                source,
+                kind: kind_or_fail(&watch_args.filter_filename, asked_kind),
+                ttl: args.ttl,
+                wait: !args.no_wait,
+                use_mmap: !(args.no_mmap || args.check),
+                socket: socket.as_deref(),
+                show_bits: args.show_bits,
+            };
+            watch_and_quit(watch_args, &options);
+        },
+        Some(Command::GitIndex(git_args)) => {
+            if args.wait && args.no_wait {
+                fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+            }
+            let socket = socket_or_default(args.socket.clone());
+            source.normalize = normalize_or_fail(&git_args.filter_filename, args.normalize, 5);
+            check_source_or_fail(&source);
+            let options = InsertOptions {
+                source,
+                kind: kind_or_fail(&git_args.filter_filename, asked_kind),
+                ttl: args.ttl,
+                wait: !args.no_wait,
+                use_mmap: !(args.no_mmap || args.check),
+                socket: socket.as_deref(),
+                show_bits: args.show_bits,
+            };
+            git_index_and_quit(git_args, &options);
+        },
+        Some(Command::AddIfAbsent(add_args)) => {
+            if args.wait && args.no_wait {
+                fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+            }
+            let socket = socket_or_default(args.socket.clone());
+            source.normalize = normalize_or_fail(&add_args.filter_filename, args.normalize, 5);
+            check_source_or_fail(&source);
+            add_if_absent_and_quit(
+                &add_args.filter_filename,
+                &add_args.file_to_insert,
+                &InsertOptions {
+                    source,
+                    kind: kind_or_fail(&add_args.filter_filename, asked_kind),
+                    ttl: args.ttl,
+                    wait: !args.no_wait,
+                    use_mmap: !(args.no_mmap || args.check),
+                    socket: socket.as_deref(),
+                    show_bits: args.show_bits,
+                },
+                add_args.quiet
+            );
+        },
+        None => {},
+    }
+
+    let filter_filename = match args.filter_filename {
+        Some(filter_filename) => filter_filename,
+        None => {
+            fail(USAGE_EXIT_CODE, "A filter must be given with -x");
+        },
+    };
+
+    let corrupt_code = if args.file_to_insert.is_some() {5} else {16};
+    source.normalize = normalize_or_fail(&filter_filename, args.normalize, corrupt_code);
+    check_source_or_fail(&source);
+    let kind = kind_or_fail(&filter_filename, asked_kind);
+
+    let ff_path = Path::new(&filter_filename);
+    let create_new_filter = if ff_path.exists() {
+        regular_file_or_fail(&filter_filename);
+        false
+    }
+    else {
+        true
+    };
+
+    if args.wait && args.no_wait {
+        fail(USAGE_EXIT_CODE, "Cannot both --wait and --no-wait");
+    }
+    if args.any && !args.quiet {
+        fail(USAGE_EXIT_CODE, "--any only goes with -s");
+    }
+    let wait = !args.no_wait;
+    let socket = socket_or_default(args.socket);
+
+    if let (Some(to_insert), Some(to_query)) = (&args.file_to_insert, args.file_to_query.first()) {
+        fail(USAGE_EXIT_CODE, format!(
+            "Cannot both insert and query for {}/{}",
+            to_insert,
+            to_query
+        ));
+    }
+
+    if create_new_filter {
+        if let Some(to_query) = args.file_to_query.first() {
+            fail(9, format!(
+                "Should not ask if '{}' is in an empty filter you're about to create at {}",
+                to_query,
+                filter_filename
+            ));
+        }
+        new_filter_and_quit(
+            &filter_filename,
+            args.file_to_insert,
+            source,
+            kind,
+            args.show_bits
+        );
+    }
+    else if let Some(to_insert) = args.file_to_insert {
+        insert_existing_filter_and_quit(
+            &filter_filename,
+            &to_insert,
+            &InsertOptions {
+                source,
+                kind,
+                ttl: args.ttl,
+                wait,
+                use_mmap: !(args.no_mmap || args.check),
+                socket: socket.as_deref(),
+                show_bits: args.show_bits,
+            }
+        );
+    }
+    else if !args.file_to_query.is_empty() {
+        query_existing_filter_and_quit(
+            &filter_filename,
+            &args.file_to_query,
+            &QueryOptions {
+                source,
+                wait,
+                use_mmap: !(args.no_mmap || args.check),
+                read_lazily: !args.check,
+                socket: socket.as_deref(),
+                show_bits: args.show_bits,
+                quiet: args.quiet,
+                any: args.any,
+            }
+        );
+    }
+    else {
+        fail(14, "Nothing to do with an existing filter if no file given to query or insert.");
     }
 }
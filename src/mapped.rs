@@ -0,0 +1,228 @@
+/* Memory-mapped access to filter files
+*
+*  Mapping a filter saves copying it into memory of its own.  Inserts set
+*  bits in place so only the pages they dirty (and the header) get written
+*  back, though they do still read every page to update the checksum, once
+*  for each batch of items.  Queries read only the header and the pages the
+*  item's bits are on.  Neither checks the checksum, which would mean reading
+*  every page; that's left to `bloom-cli verify`.
+*
+*  Only plain filters are mapped.  Other kinds (see kind.rs) are read whole.
+*/
+use memmap2::Mmap;
+use memmap2::MmapMut;
+use std::fs::File;
+
+use crate::format;
+use crate::kind::Kind;
+use crate::{bit_positions, bit_set, set_bit};
+
+
+/// Map `file` read-only, or None if it can't be mapped (it's empty, the
+/// filesystem doesn't support it, it isn't a plain filter...) and should be
+/// read normally instead.
+pub fn map(file: &File) -> Option<Mmap> {
+    /* SAFETY: The caller holds at least a shared lock on the filter (see
+     * lock.rs), and anything that modifies a filter takes an exclusive one
+     * first, so `file` won't change size or contents underneath us. */
+    let map = unsafe { Mmap::map(file) }.ok()?;
+    if is_plain(&map) {
+        Some(map)
+    }
+    else {
+        None
+    }
+}
+
+
+/// Map `file` read-write, or None if it can't be mapped, or if it's a
+/// legacy filter that needs a header added, which can't be done in place.
+pub fn map_mut(file: &File) -> Option<MmapMut> {
+    /* SAFETY: The caller holds an exclusive lock on the filter. */
+    let map = unsafe { MmapMut::map_mut(file) }.ok()?;
+    if map.starts_with(format::MAGIC) && is_plain(&map) {
+        Some(map)
+    }
+    else {
+        None
+    }
+}
+
+
+/* Not some other kind of filter?  Ones that can't be parsed at all are left
+ * for whatever uses the map to report. */
+fn is_plain(map: &[u8]) -> bool {
+    match format::parse_header(map, map.len()) {
+        Ok((header, _)) => header.kind == Kind::Plain,
+        Err(_) => true,
+    }
+}
+
+
+/// Is `bytes` (probably) in the mapped filter file `map`?
+pub fn query(map: &[u8], bytes: &[u8]) -> Result<bool, String> {
+    let (header, body) = format::split_filter(map)?;
+    for (whichint, whichbit) in bit_positions(bytes, header.m) {
+        if !bit_set(format::word_at(body, whichint)?, whichbit)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+
+/// Insert `bytes` into the mapped filter file `map`, in place.
+pub fn insert(map: &mut MmapMut, bytes: &[u8]) -> Result<(), String> {
+    test_and_insert(map, bytes).map(|_| ())
+}
+
+
+/// Insert `bytes` into the mapped filter file `map`, in place, and return
+/// whether it was (probably) in already, in which case nothing's written.
+pub fn test_and_insert(map: &mut MmapMut, bytes: &[u8]) -> Result<bool, String> {
+    test_and_insert_all(map, [bytes]).map(|was_in| was_in[0])
+}
+
+
+/// Insert every item in `items` into the mapped filter file `map`, in place.
+pub fn insert_all<'a, I: IntoIterator<Item = &'a [u8]>>(map: &mut MmapMut, items: I) -> Result<(), String> {
+    test_and_insert_all(map, items).map(|_| ())
+}
+
+
+/// Insert every item in `items` into the mapped filter file `map`, in
+/// place, and return whether each was (probably) in already, counting those
+/// before it.  The checksum's updated, and the map flushed, just once for
+/// all of them, and not at all if they were all in.
+pub fn test_and_insert_all<'a, I: IntoIterator<Item = &'a [u8]>>(
+    map: &mut MmapMut,
+    items: I
+) -> Result<Vec<bool>, String> {
+    let (header, body) = format::split_filter(map)?;
+    let body_start = map.len() - body.len();
+
+    let body = &mut map[body_start..];
+    let mut all_in = true;
+    let mut were_in = Vec::new();
+    for bytes in items {
+        let mut was_in = true;
+        for (whichint, whichbit) in bit_positions(bytes, header.m) {
+            let int = format::word_at(body, whichint)?;
+            if !bit_set(int, whichbit)? {
+                was_in = false;
+                format::set_word_at(body, whichint, set_bit(int, whichbit)?)?;
+            }
+        }
+        all_in &= was_in;
+        were_in.push(was_in);
+    }
+    if all_in {
+        return Ok(were_in);
+    }
+    format::update_checksum(map)?;
+
+    map.flush().map_err(|err| err.to_string())?;
+    Ok(were_in)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_mapped_insert_and_query() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-mapped-{}", std::process::id()));
+        let m = NonZeroUsize::new(1000).unwrap();
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        assert!(format::write_filter(&file, m, &[0; 16]).is_ok());
+
+        let mut map = map_mut(&file).unwrap();
+        assert_eq!(query(&map, b"known"), Ok(false));
+        assert!(insert(&mut map, b"known").is_ok());
+        assert_eq!(query(&map, b"known"), Ok(true));
+        assert_eq!(query(&map, b"unknown"), Ok(false));
+        assert_eq!(test_and_insert(&mut map, b"known"), Ok(true));
+        assert_eq!(test_and_insert(&mut map, b"other"), Ok(false));
+        assert_eq!(test_and_insert(&mut map, b"other"), Ok(true));
+        assert_eq!(
+            test_and_insert_all(&mut map, [&b"other"[..], b"third", b"third"]),
+            Ok(vec![true, false, true])
+        );
+        assert!(insert_all(&mut map, [&b"fourth"[..], b"fifth"]).is_ok());
+        drop(map);
+
+        /* Same bits as the buffered path would have set, valid checksum */
+        let mut on_disk = Vec::<u8>::new();
+        File::open(&path).unwrap().read_to_end(&mut on_disk).unwrap();
+        let (header, filter) = format::parse_filter(&on_disk).unwrap();
+        assert_eq!(header.m, m);
+        let mut expected = vec![0; 16];
+        assert!(crate::filter_insert(b"known", &mut expected, m).is_ok());
+        assert!(crate::filter_insert(b"other", &mut expected, m).is_ok());
+        for bytes in [&b"third"[..], b"fourth", b"fifth"] {
+            assert!(crate::filter_insert(bytes, &mut expected, m).is_ok());
+        }
+        assert_eq!(filter, expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_query_reads_only_its_words() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-mapped-query-{}", std::process::id()));
+        let m = NonZeroUsize::new(1000).unwrap();
+        let mut filter = vec![0; 16];
+        assert!(crate::filter_insert(b"known", &mut filter, m).is_ok());
+        let mut on_disk = Vec::<u8>::new();
+        assert!(format::write_filter(&mut on_disk, m, &filter).is_ok());
+
+        /* Flip a bit in a word "known" doesn't hash to, so only a query that
+         * hashed the whole body would notice */
+        let (header, body) = format::split_filter(&on_disk).unwrap();
+        let touched: Vec<usize> = bit_positions(b"known", header.m).into_iter().map(|(whichint, _)| whichint).collect();
+        let untouched = (0..16).find(|whichint| !touched.contains(whichint)).unwrap();
+        let body_start = on_disk.len() - body.len();
+        on_disk[body_start + untouched * 8] ^= 1;
+        assert!(format::verify_filter(&on_disk).is_err());
+        std::fs::write(&path, &on_disk).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let map = map(&file).unwrap();
+        assert_eq!(query(&map, b"known"), Ok(true));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_other_kinds_not_mapped() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-mapped-kind-{}", std::process::id()));
+        let m = NonZeroUsize::new(64).unwrap();
+        let kind = Kind::Rotating(crate::rotating::Rotation::new(2, 0, 0).unwrap());
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        assert!(format::write_kind_filter(&file, m, crate::normalize::Normalize::NONE, kind, &[0; 2]).is_ok());
+        assert!(map(&file).is_none());
+        assert!(map_mut(&file).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_in_place_legacy_inserts() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-mapped-legacy-{}", std::process::id()));
+        std::fs::write(&path, [0; 64]).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        assert!(map_mut(&file).is_none());
+        assert!(map(&file).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
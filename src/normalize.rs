@@ -0,0 +1,239 @@
+/* Normalizing text items before they're hashed
+*
+*  Copies of a text file that differ only in line endings, trailing
+*  whitespace, case or how accents are composed are the same to a person
+*  but unrelated to a hash.  A filter can be made with normalizers, which are
+*  recorded in its header and applied to every item before it's hashed, so
+*  queries of it normalize the same way without being asked to.
+*
+*  They're applied in a fixed order: nfc, line-endings, whitespace, case.
+*  line-endings and whitespace work on any bytes, but items that aren't UTF-8
+*  aren't text, so nfc and case leave them be.
+*/
+use caseless::default_case_fold_str;
+use std::fmt;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalizer {
+    /// Unicode Normalization Form C, so composed and decomposed accents match
+    Nfc,
+    /// CRLF and lone CR become LF
+    LineEndings,
+    /// Runs of spaces and tabs become one space, and any at the ends of
+    /// lines are dropped
+    Whitespace,
+    /// Unicode full case folding, so "Straße" and "STRASSE" match
+    Case,
+}
+
+
+/* The order normalizers are applied in, and their bits in a header */
+const NORMALIZERS: [Normalizer; 4] = [
+    Normalizer::Nfc,
+    Normalizer::LineEndings,
+    Normalizer::Whitespace,
+    Normalizer::Case,
+];
+
+
+impl Normalizer {
+    fn name(&self) -> &'static str {
+        match self {
+            Normalizer::Nfc => "nfc",
+            Normalizer::LineEndings => "line-endings",
+            Normalizer::Whitespace => "whitespace",
+            Normalizer::Case => "case",
+        }
+    }
+
+
+    fn bit(&self) -> u32 {
+        1 << NORMALIZERS.iter().position(|normalizer| normalizer == self).unwrap_or_default()
+    }
+}
+
+
+/// The normalizers a filter applies to its items
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Normalize {
+    flags: u32,
+}
+
+
+impl FromStr for Normalize {
+    type Err = String;
+
+    /// Comma separated normalizer names, like "line-endings,whitespace"
+    fn from_str(names: &str) -> Result<Normalize, String> {
+        let mut flags = 0;
+        for name in names.split(',') {
+            match NORMALIZERS.iter().find(|normalizer| normalizer.name() == name) {
+                Some(normalizer) => flags |= normalizer.bit(),
+                None => {
+                    let known: Vec<&str> = NORMALIZERS.iter().map(Normalizer::name).collect();
+                    return Err(format!("'{}' isn't one of {}", name, known.join(", ")));
+                },
+            }
+        }
+        Ok(Normalize {flags})
+    }
+}
+
+
+impl fmt::Display for Normalize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_none() {
+            return write!(f, "none");
+        }
+        let names: Vec<&str> = NORMALIZERS.iter()
+            .filter(|normalizer| self.has(**normalizer))
+            .map(Normalizer::name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+
+impl Normalize {
+    pub const NONE: Normalize = Normalize {flags: 0};
+
+
+    /// The normalizers in a header's flags, which mustn't include any this
+    /// version doesn't know about
+    pub fn from_flags(flags: u32) -> Result<Normalize, String> {
+        let known = NORMALIZERS.iter().fold(0, |known, normalizer| known | normalizer.bit());
+        if flags & !known != 0 {
+            return Err(format!("Filter uses unknown normalizers ({:#x})", flags & !known));
+        }
+        Ok(Normalize {flags})
+    }
+
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+
+    pub fn is_none(&self) -> bool {
+        self.flags == 0
+    }
+
+
+    pub fn has(&self, normalizer: Normalizer) -> bool {
+        self.flags & normalizer.bit() != 0
+    }
+
+
+    /// `bytes` normalized, ready to hash
+    pub fn apply(&self, bytes: Vec<u8>) -> Vec<u8> {
+        if self.is_none() {
+            return bytes;
+        }
+        let mut bytes = bytes;
+        if self.has(Normalizer::Nfc) {
+            bytes = map_text(bytes, |text| text.nfc().collect());
+        }
+        if self.has(Normalizer::LineEndings) {
+            bytes = normalize_line_endings(&bytes);
+        }
+        if self.has(Normalizer::Whitespace) {
+            bytes = collapse_whitespace(&bytes);
+        }
+        if self.has(Normalizer::Case) {
+            bytes = map_text(bytes, default_case_fold_str);
+            /* Folding can leave a few characters decomposed again */
+            if self.has(Normalizer::Nfc) {
+                bytes = map_text(bytes, |text| text.nfc().collect());
+            }
+        }
+        bytes
+    }
+}
+
+
+/* `change` applied to `bytes` if they're text, otherwise `bytes` as is */
+fn map_text(bytes: Vec<u8>, change: impl Fn(&str) -> String) -> Vec<u8> {
+    match String::from_utf8(bytes) {
+        Ok(text) => change(&text).into_bytes(),
+        Err(err) => err.into_bytes(),
+    }
+}
+
+
+fn normalize_line_endings(bytes: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut after_cr = false;
+    for byte in bytes.iter() {
+        match byte {
+            b'\r' => normalized.push(b'\n'),
+            b'\n' if after_cr => {},
+            _ => normalized.push(*byte),
+        }
+        after_cr = *byte == b'\r';
+    }
+    normalized
+}
+
+
+fn collapse_whitespace(bytes: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(bytes.len());
+    let mut in_run = false;
+    for byte in bytes.iter() {
+        if *byte == b' ' || *byte == b'\t' {
+            in_run = true;
+            continue;
+        }
+        /* Runs only count if something other than a line end follows */
+        if in_run && *byte != b'\n' && *byte != b'\r' {
+            collapsed.push(b' ');
+        }
+        in_run = false;
+        collapsed.push(*byte);
+    }
+    collapsed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(names: &str, text: &str) -> Vec<u8> {
+        names.parse::<Normalize>().unwrap().apply(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_normalizers() {
+        assert_eq!(normalized("line-endings", "a\r\nb\rc\n\r\n"), b"a\nb\nc\n\n".to_vec());
+        assert_eq!(normalized("whitespace", "  a \t b  \n\tc\t\r\nd "), b" a b\n c\r\nd".to_vec());
+        assert_eq!(normalized("case", "ÀbC"), "àbc".as_bytes().to_vec());
+        assert_eq!(normalized("case", "Straße"), normalized("case", "STRASSE"));
+        assert_eq!(normalized("case", "ΣΊΣΥΦΟΣ"), normalized("case", "σίσυφος"));
+        assert_eq!(normalized("nfc,case", "J\u{030C}"), "\u{01F0}".as_bytes().to_vec());
+        assert_eq!(normalized("nfc", "A\u{0300}"), "À".as_bytes().to_vec());
+
+        /* Order they're given in doesn't matter */
+        let all = "A\u{0300} b  \r\nC\t\r\n";
+        assert_eq!(normalized("case,whitespace,line-endings,nfc", all), "à b\nc\n".as_bytes().to_vec());
+        assert_eq!(normalized("nfc,line-endings,whitespace,case", all), normalized("case,nfc,whitespace,line-endings", all));
+
+        /* Binary items only get the normalizers that work on bytes */
+        let everything: Normalize = "nfc,line-endings,whitespace,case".parse().unwrap();
+        assert_eq!(Normalize::NONE.apply(vec![0xff, b'\r']), vec![0xff, b'\r']);
+        assert_eq!(everything.apply(vec![0xff, b'A', b' ', b'\r']), vec![0xff, b'A', b'\n']);
+    }
+
+    #[test]
+    fn test_names_and_flags() {
+        let normalize: Normalize = "whitespace,nfc".parse().unwrap();
+        assert!(normalize.has(Normalizer::Nfc) && !normalize.has(Normalizer::Case));
+        assert_eq!(normalize.to_string(), "nfc,whitespace");
+        assert_eq!(Normalize::NONE.to_string(), "none");
+        assert_eq!(Normalize::from_flags(normalize.flags()), Ok(normalize));
+        assert!(Normalize::from_flags(1 << 20).is_err());
+        assert!("upper".parse::<Normalize>().is_err());
+    }
+}
@@ -0,0 +1,460 @@
+/* A Redis protocol (RESP) front end on a server's filters, so redis clients
+*  that speak RedisBloom's BF.* commands can use them
+*
+*    BF.ADD key item                 -> 1 if item is new, 0 if not
+*    BF.MADD key item [item ...]     -> an array of the same
+*    BF.EXISTS key item              -> 1 if item is probably in, 0 if not
+*    BF.MEXISTS key item [item ...]  -> an array of the same
+*    BF.INFO key                     -> capacity, size, etc.
+*
+*  plus PING, QUIT and enough of COMMAND to keep redis-cli happy.
+*
+*  A key is either the path of a filter being served or just its file name.
+*  Filters can't be created (or reserved) this way, so BF.ADD to an unknown
+*  key is an error, while BF.EXISTS on one is 0 like RedisBloom's.
+*/
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use crate::server::FilterSet;
+
+
+/// The false positive rate the default m and k were chosen for, see `M`
+const ERROR_RATE: f64 = 0.01;
+
+/// The longest bulk string a command can have, as Redis's
+/// proto-max-bulk-len defaults to
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// The longest line, inline command or header, as Redis allows
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The most arguments a command can have, as Redis allows
+const MAX_ARGS: usize = 1024 * 1024;
+
+
+/// A RESP value to send back
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Array(Vec<Reply>),
+}
+
+
+impl Reply {
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(string) => {
+                out.extend_from_slice(format!("+{}\r\n", string).as_bytes());
+            },
+            Reply::Error(message) => {
+                out.extend_from_slice(format!("-{}\r\n", message.replace(['\r', '\n'], " ")).as_bytes());
+            },
+            Reply::Integer(int) => {
+                out.extend_from_slice(format!(":{}\r\n", int).as_bytes());
+            },
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.write_to(out);
+                }
+            },
+        }
+    }
+}
+
+
+/// Read one line ending in \r\n (or just \n), without the line ending.
+/// None at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let mut line = Vec::new();
+    match reader.take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            if line.len() > MAX_LINE_LEN {
+                return Err("Protocol error: too big inline request".to_owned());
+            }
+            if line.last() != Some(&b'\n') {
+                return Err("Connection closed mid-command".to_owned());
+            }
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            Ok(Some(line))
+        },
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+
+/// The number after the type byte of a RESP header line like "*3" or "$5"
+fn header_number(line: &[u8], kind: u8) -> Result<usize, String> {
+    if line.first() != Some(&kind) {
+        return Err(format!(
+            "Protocol error: expected '{}', got '{}'",
+            kind as char,
+            String::from_utf8_lossy(line)
+        ));
+    }
+    std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| format!("Protocol error: bad length '{}'", String::from_utf8_lossy(line)))
+}
+
+
+/// Read one command, either an array of bulk strings or an inline command
+/// like "PING".  None at the end of the stream.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, String> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => {
+            return Ok(None);
+        },
+    };
+
+    if !line.starts_with(b"*") {
+        return Ok(Some(
+            line.split(|byte| byte.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(|word| word.to_vec())
+                .collect()
+        ));
+    }
+
+    let num_args = header_number(&line, b'*')?;
+    if num_args > MAX_ARGS {
+        return Err("Protocol error: invalid multibulk length".to_owned());
+    }
+    let mut args = Vec::with_capacity(num_args.min(1024));
+    for _ in 0..num_args {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => {
+                return Err("Connection closed mid-command".to_owned());
+            },
+        };
+        let with_crlf = match header_number(&line, b'$')? {
+            len if len <= MAX_BULK_LEN => len.checked_add(2),
+            _ => None,
+        };
+        let len = match with_crlf {
+            Some(with_crlf) => with_crlf - 2,
+            None => {
+                return Err("Protocol error: invalid bulk length".to_owned());
+            },
+        };
+        /* Read rather than allocate up front, so a length with nothing
+         * behind it costs nothing */
+        let mut arg = Vec::new();
+        match reader.take(len as u64 + 2).read_to_end(&mut arg) {
+            Ok(read) if read == len + 2 => {},
+            Ok(_) => {
+                return Err("Connection closed mid-command".to_owned());
+            },
+            Err(err) => {
+                return Err(err.to_string());
+            },
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err("Protocol error: bulk string not terminated by CRLF".to_owned());
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+
+/// The served filter `key` refers to, by path or file name
+fn filter_for_key(filters: &FilterSet, key: &[u8]) -> Option<String> {
+    let key = std::str::from_utf8(key).ok()?;
+    if filters.serves(key) {
+        return Some(key.to_owned());
+    }
+    filters.paths()
+        .find(|path| path.file_name() == Some(Path::new(key).as_os_str()))
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", name))
+}
+
+
+fn info(filters: &FilterSet, filter: &str) -> Reply {
+    let stats = match filters.stats_for(filter) {
+        Ok(stats) => stats,
+        Err(err) => {
+            return Reply::Error(format!("ERR {}", err));
+        },
+    };
+    let m = usize::from(stats.m) as f64;
+    let capacity = m * 2f64.ln().powi(2) / -ERROR_RATE.ln();
+
+    Reply::Array(vec![
+        Reply::Simple("Capacity".to_owned()),
+        Reply::Integer(capacity as i64),
+        Reply::Simple("Size".to_owned()),
+        Reply::Integer(crate::num_u64s(stats.m) as i64 * 8),
+        Reply::Simple("Number of filters".to_owned()),
+        Reply::Integer(1),
+        Reply::Simple("Number of items inserted".to_owned()),
+        Reply::Integer(stats.estimated_items().round() as i64),
+        Reply::Simple("Expansion rate".to_owned()),
+        Reply::Integer(0),
+    ])
+}
+
+
+/// The reply to one command
+pub fn execute(filters: &FilterSet, command: &[Vec<u8>]) -> Reply {
+    let name = match command.first() {
+        Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+        None => {
+            return Reply::Error("ERR empty command".to_owned());
+        },
+    };
+    let args = &command[1..];
+
+    match name.as_str() {
+        "PING" => Reply::Simple("PONG".to_owned()),
+        "QUIT" => Reply::Simple("OK".to_owned()),
+        "COMMAND" => Reply::Array(vec![]),
+        "BF.ADD" | "BF.MADD" | "BF.EXISTS" | "BF.MEXISTS" => {
+            let single = name == "BF.ADD" || name == "BF.EXISTS";
+            if args.len() < 2 || (single && args.len() != 2) {
+                return wrong_arity(&name.to_ascii_lowercase());
+            }
+            let adding = name.ends_with("ADD");
+            let filter = match filter_for_key(filters, &args[0]) {
+                Some(filter) => filter,
+                None if adding => {
+                    return Reply::Error("ERR no such filter, filters can't be created over RESP".to_owned());
+                },
+                None => {
+                    let zeroes = args[1..].iter().map(|_| Reply::Integer(0)).collect();
+                    return if single {Reply::Integer(0)} else {Reply::Array(zeroes)};
+                },
+            };
+
+            let mut replies = Vec::new();
+            for item in args[1..].iter() {
+                let result = filters.item_hashes(&filter, item).and_then(|hashes| {
+                    if adding {
+                        filters.insert(&filter, &hashes)
+                    }
+                    else {
+                        filters.query(&filter, &hashes)
+                    }
+                });
+                replies.push(match result {
+                    Ok(yes) => Reply::Integer(yes as i64),
+                    Err(err) => Reply::Error(format!("ERR {}", err)),
+                });
+            }
+            if single {replies.remove(0)} else {Reply::Array(replies)}
+        },
+        "BF.INFO" => {
+            if args.len() != 1 {
+                return wrong_arity("bf.info");
+            }
+            match filter_for_key(filters, &args[0]) {
+                Some(filter) => info(filters, &filter),
+                None => Reply::Error("ERR not found".to_owned()),
+            }
+        },
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+
+fn handle_connection(stream: TcpStream, filters: &FilterSet) {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    loop {
+        let mut out = Vec::new();
+        let quitting = match read_command(&mut reader) {
+            Ok(Some(command)) if command.is_empty() => {
+                continue;
+            },
+            Ok(Some(command)) => {
+                execute(filters, &command).write_to(&mut out);
+                command[0].eq_ignore_ascii_case(b"QUIT")
+            },
+            Ok(None) => {
+                return;
+            },
+            Err(err) => {
+                /* Can't tell where the next command starts, so give up */
+                Reply::Error(format!("ERR {}", err)).write_to(&mut out);
+                true
+            },
+        };
+        if writer.write_all(&out).is_err() || quitting {
+            return;
+        }
+    }
+}
+
+
+pub fn bind(address: &str) -> Result<TcpListener, String> {
+    TcpListener::bind(address).map_err(|err| {
+        format!("Unable to listen for RESP on '{}' ({})", address, err)
+    })
+}
+
+
+/// Answer RESP commands on `listener` forever
+pub fn serve(listener: TcpListener, filters: Arc<FilterSet>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let filters = Arc::clone(&filters);
+                thread::spawn(move || handle_connection(stream, &filters));
+            },
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::num::NonZeroUsize;
+
+    fn temp_filter(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-resp-{}-{}", name, std::process::id()));
+        let m = NonZeroUsize::new(4096).unwrap();
+        assert!(format::write_filter(File::create(&path).unwrap(), m, &[0; 64]).is_ok());
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn command(words: &[&str]) -> Vec<Vec<u8>> {
+        words.iter().map(|word| word.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_read_command() {
+        let mut input = &b"*3\r\n$6\r\nBF.ADD\r\n$1\r\nf\r\n$4\r\na\r\nb\r\nPING\r\n  \r\n"[..];
+        assert_eq!(read_command(&mut input), Ok(Some(command(&["BF.ADD", "f", "a\r\nb"]))));
+        assert_eq!(read_command(&mut input), Ok(Some(command(&["PING"]))));
+        assert_eq!(read_command(&mut input), Ok(Some(vec![])));
+        assert_eq!(read_command(&mut input), Ok(None));
+
+        assert!(read_command(&mut &b"*1\r\n:5\r\n"[..]).is_err());
+        assert!(read_command(&mut &b"*1\r\n$5\r\nab"[..]).is_err());
+        assert!(read_command(&mut &b"*2\r\n$1\r\na\r\n"[..]).is_err());
+        assert!(read_command(&mut &b"*x\r\n"[..]).is_err());
+
+        /* Lengths no client should send */
+        assert!(read_command(&mut &b"*1\r\n$536870913\r\nab\r\n"[..]).is_err());
+        let too_long = format!("*1\r\n${}\r\nab\r\n", usize::MAX);
+        assert!(read_command(&mut too_long.as_bytes()).is_err());
+        assert!(read_command(&mut &b"*1048577\r\n"[..]).is_err());
+        let inline = vec![b'a'; MAX_LINE_LEN + 1];
+        assert!(read_command(&mut &inline[..]).is_err());
+        assert!(read_command(&mut &inline[1..]).is_err());
+    }
+
+    #[test]
+    fn test_write_reply() {
+        let mut out = Vec::new();
+        Reply::Array(vec![
+            Reply::Integer(1),
+            Reply::Simple("OK".to_owned()),
+            Reply::Error("ERR no\r\n".to_owned()),
+        ]).write_to(&mut out);
+        assert_eq!(out, b"*3\r\n:1\r\n+OK\r\n-ERR no  \r\n");
+    }
+
+    #[test]
+    fn test_execute() {
+        let filter = temp_filter("execute");
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        let key = Path::new(&filter).file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(execute(&filters, &command(&["ping"])), Reply::Simple("PONG".to_owned()));
+        assert_eq!(execute(&filters, &command(&["BF.EXISTS", key, "a"])), Reply::Integer(0));
+        assert_eq!(execute(&filters, &command(&["BF.ADD", key, "a"])), Reply::Integer(1));
+        assert_eq!(execute(&filters, &command(&["bf.add", &filter, "a"])), Reply::Integer(0));
+        assert_eq!(execute(&filters, &command(&["BF.EXISTS", key, "a"])), Reply::Integer(1));
+        assert_eq!(
+            execute(&filters, &command(&["BF.MADD", key, "a", "b", "c"])),
+            Reply::Array(vec![Reply::Integer(0), Reply::Integer(1), Reply::Integer(1)])
+        );
+        assert_eq!(
+            execute(&filters, &command(&["BF.MEXISTS", key, "c", "d"])),
+            Reply::Array(vec![Reply::Integer(1), Reply::Integer(0)])
+        );
+
+        /* Unknown keys */
+        assert_eq!(execute(&filters, &command(&["BF.EXISTS", "nope", "a"])), Reply::Integer(0));
+        assert_eq!(
+            execute(&filters, &command(&["BF.MEXISTS", "nope", "a", "b"])),
+            Reply::Array(vec![Reply::Integer(0), Reply::Integer(0)])
+        );
+        assert!(matches!(execute(&filters, &command(&["BF.ADD", "nope", "a"])), Reply::Error(_)));
+        assert!(matches!(execute(&filters, &command(&["BF.INFO", "nope"])), Reply::Error(_)));
+
+        /* Bad arity, unknown commands */
+        assert!(matches!(execute(&filters, &command(&["BF.ADD", key])), Reply::Error(_)));
+        assert!(matches!(execute(&filters, &command(&["BF.ADD", key, "a", "b"])), Reply::Error(_)));
+        assert!(matches!(execute(&filters, &command(&["BF.MEXISTS", key])), Reply::Error(_)));
+        assert!(matches!(execute(&filters, &command(&["SET", "a", "b"])), Reply::Error(_)));
+
+        match execute(&filters, &command(&["BF.INFO", key])) {
+            Reply::Array(info) => {
+                assert_eq!(info[3], Reply::Integer(512));
+                assert_eq!(info[7], Reply::Integer(3));
+            },
+            reply => panic!("{:?}", reply),
+        }
+
+        fs::remove_file(crate::lock::lock_path(std::path::Path::new(&filter))).unwrap();
+        fs::remove_file(&filter).unwrap();
+    }
+
+    #[test]
+    fn test_serve_over_tcp() {
+        let filter = temp_filter("tcp");
+        let key = Path::new(&filter).file_name().unwrap().to_str().unwrap().to_owned();
+        let filters = Arc::new(FilterSet::load(std::slice::from_ref(&filter)).unwrap());
+        let listener = bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, filters));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "*3\r\n$6\r\nBF.ADD\r\n${}\r\n{}\r\n$1\r\nx\r\nPING\r\nQUIT\r\n",
+            key.len(),
+            key
+        ).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, ":1\r\n+PONG\r\n+OK\r\n");
+
+        fs::remove_file(crate::lock::lock_path(std::path::Path::new(&filter))).unwrap();
+        fs::remove_file(&filter).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+/* Rotating filters, for "seen recently" rather than "ever seen"
+*
+*  A rotating filter is several generations of bit array in one file, newest
+*  first.  Items go into the newest, and are in the filter if they're in any
+*  of them.  Rotating drops the oldest generation and starts an empty new one,
+*  so an item's forgotten once every generation it went into has been
+*  dropped: after `generations` rotations at most.
+*
+*  Rotating can be left to `bloom-cli rotate`, e.g. from cron, or the header
+*  can give an interval.  Then whenever the filter's inserted into, it's
+*  first rotated as many times as intervals have passed since it last was,
+*  and queries skip the generations that would have been dropped by now, so
+*  they needn't write anything.
+*/
+use std::fmt;
+use std::num::NonZeroUsize;
+
+use crate::{filter_insert_hashes, hashes_in_filter, num_u64s, ItemHashes};
+
+pub const MAX_GENERATIONS: u32 = 1024;
+
+
+/// How a rotating filter rotates, and when it last did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub generations: u32,
+    /// Seconds between rotations, 0 for only when asked to
+    pub interval: u64,
+    /// When it last rotated, in seconds since the epoch
+    pub rotated_at: u64,
+}
+
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.interval == 0 {
+            write!(f, "rotating {} generations when asked", self.generations)
+        }
+        else {
+            write!(f, "rotating {} generations every {}s", self.generations, self.interval)
+        }
+    }
+}
+
+
+impl Rotation {
+    pub fn new(generations: u32, interval: u64, now: u64) -> Result<Rotation, String> {
+        if generations == 0 || generations > MAX_GENERATIONS {
+            return Err(format!("A rotating filter needs 1 to {} generations", MAX_GENERATIONS));
+        }
+        Ok(Rotation {generations, interval, rotated_at: now})
+    }
+
+
+    /// How many rotations are due at `now`, at most one per generation
+    fn due(&self, now: u64) -> usize {
+        if self.interval == 0 {
+            return 0;
+        }
+        (now.saturating_sub(self.rotated_at) / self.interval).min(self.generations as u64) as usize
+    }
+
+
+    /// Is an item with `hashes` (probably) in any generation that's still
+    /// live at `now`?
+    pub fn contains(&self, hashes: &ItemHashes, filter: &[u64], m: NonZeroUsize, now: u64) -> Result<bool, String> {
+        let live = self.generations as usize - self.due(now);
+        for generation in filter.chunks(num_u64s(m)).take(live) {
+            if hashes_in_filter(hashes, generation, m)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+
+    /// Insert an item with `hashes` into the newest generation, after any
+    /// rotations due at `now`, and return whether it was (probably) in
+    /// already.  It goes in even if it was, so it's kept for as long as if
+    /// it were new.
+    pub fn test_and_insert(
+        &mut self,
+        hashes: &ItemHashes,
+        filter: &mut [u64],
+        m: NonZeroUsize,
+        now: u64
+    ) -> Result<bool, String> {
+        let due = self.due(now);
+        if due > 0 {
+            shift(filter, m, due);
+            /* Keep to the same schedule however late this is */
+            self.rotated_at = now - now.saturating_sub(self.rotated_at) % self.interval;
+        }
+        let was_in = self.contains(hashes, filter, m, now)?;
+        let newest = num_u64s(m).min(filter.len());
+        filter_insert_hashes(hashes, &mut filter[..newest], m)?;
+        Ok(was_in)
+    }
+
+
+    /// Drop the oldest generation at `now`, along with any already due
+    pub fn rotate(&mut self, filter: &mut [u64], m: NonZeroUsize, now: u64) {
+        let due = self.due(now);
+        shift(filter, m, (due + 1).min(self.generations as usize));
+        self.rotated_at = now;
+    }
+}
+
+
+/* Move every generation `by` older, dropping those that fall off the end
+ * and leaving empty ones behind */
+fn shift(filter: &mut [u64], m: NonZeroUsize, by: usize) {
+    let by_words = (by * num_u64s(m)).min(filter.len());
+    filter.rotate_right(by_words);
+    filter[..by_words].fill(0);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+
+    #[test]
+    fn test_rotate_by_hand() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let mut rotation = Rotation::new(3, 0, 100).unwrap();
+        let mut filter = vec![0; 3 * num_u64s(m)];
+        let known = item_hashes(b"known");
+
+        assert_eq!(rotation.test_and_insert(&known, &mut filter, m, 100), Ok(false));
+        assert_eq!(rotation.test_and_insert(&known, &mut filter, m, 100), Ok(true));
+        /* Time alone doesn't rotate it */
+        assert_eq!(rotation.contains(&known, &filter, m, 1_000_000), Ok(true));
+
+        rotation.rotate(&mut filter, m, 200);
+        rotation.rotate(&mut filter, m, 300);
+        assert_eq!(rotation.rotated_at, 300);
+        assert_eq!(rotation.contains(&known, &filter, m, 300), Ok(true));
+        rotation.rotate(&mut filter, m, 400);
+        assert_eq!(rotation.contains(&known, &filter, m, 400), Ok(false));
+        assert!(filter.iter().all(|int| *int == 0));
+    }
+
+    #[test]
+    fn test_rotate_over_time() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let mut rotation = Rotation::new(2, 60, 0).unwrap();
+        let mut filter = vec![0; 2 * num_u64s(m)];
+        let known = item_hashes(b"known");
+        let other = item_hashes(b"other");
+
+        assert_eq!(rotation.test_and_insert(&known, &mut filter, m, 10), Ok(false));
+        assert_eq!(rotation.contains(&known, &filter, m, 119), Ok(true));
+        /* Gone once both generations would have been dropped, though
+         * nothing's been written */
+        assert_eq!(rotation.contains(&known, &filter, m, 120), Ok(false));
+
+        /* Inserting catches up, on the same schedule */
+        assert_eq!(rotation.test_and_insert(&other, &mut filter, m, 70), Ok(false));
+        assert_eq!(rotation.rotated_at, 60);
+        assert_eq!(rotation.contains(&known, &filter, m, 70), Ok(true));
+        assert_eq!(rotation.test_and_insert(&other, &mut filter, m, 1000), Ok(false));
+        assert_eq!(rotation.rotated_at, 960);
+        assert_eq!(rotation.contains(&known, &filter, m, 1000), Ok(false));
+        assert_eq!(rotation.contains(&other, &filter, m, 1000), Ok(true));
+    }
+
+    #[test]
+    fn test_generations_checked() {
+        assert!(Rotation::new(0, 0, 0).is_err());
+        assert!(Rotation::new(MAX_GENERATIONS + 1, 0, 0).is_err());
+        assert_eq!(Rotation::new(24, 3600, 0).unwrap().to_string(), "rotating 24 generations every 3600s");
+        assert_eq!(Rotation::new(3, 0, 0).unwrap().to_string(), "rotating 3 generations when asked");
+    }
+}
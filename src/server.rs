@@ -0,0 +1,822 @@
+/* A long-running server that keeps filters in memory
+*
+*  Requests come one per line over a Unix domain socket and each gets one
+*  line back:
+*
+*    PING                       -> PONG
+*    QUERY <hashes> <filter>    -> IN | NOT IN | NOT SERVED
+*    INSERT <hashes> <filter>   -> OK | NOT SERVED
+*    ADD <hashes> <filter>      -> ADDED | ALREADY IN | NOT SERVED
+*    FLUSH                      -> OK
+*
+*  <hashes> is the item's NUM_HASHES hashes (see `item_hashes`) as 8 hex
+*  digits each, run together, so clients never have to send whole files.
+*  <filter> is the path of a filter and runs to the end of the line.  If it
+*  isn't one the server was started with the answer is NOT SERVED so the
+*  client can go to the file itself.  Anything that goes wrong is answered
+*  with "ERROR <message>", and a line over MAX_LINE_LEN bytes with that and
+*  the end of the connection.
+*
+*  Changed filters are flushed to disk every so often.  Flushing ORs the bits
+*  in memory into whatever's on disk, under the usual lock, so anything
+*  inserted into the file directly in the meantime isn't lost, and renames
+*  the result into place (see `format::replace_filter`), so a server killed
+*  mid-flush leaves the old filter or the new one rather than a mix.  Filters
+*  that haven't changed are read back in on each flush instead, so the
+*  server answers for direct inserts by the next flush at the latest.  With
+*  a write-ahead log (see wal.rs) inserts are also logged before they're
+*  acknowledged, and the log is emptied once everything's been flushed.
+*  When the server's told to stop, inserts from then on get an ERROR and
+*  everything's flushed one last time.
+*/
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::format;
+use crate::kind::{unix_now, Kind};
+use crate::lock;
+use crate::normalize::Normalize;
+use crate::wal::Wal;
+use crate::{filter_insert_hashes, hashes_in_filter, item_hashes, test_and_insert_hashes, ItemHashes, NUM_HASHES};
+
+/// How long `ask` waits on a server that's stopped answering
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request line taken, far more than a command, its hashes and any
+/// path need
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+
+/// A filter as the server holds it
+#[derive(Debug)]
+struct HeldFilter {
+    m: NonZeroUsize,
+    normalize: Normalize,
+    bits: Vec<u64>,
+    /// Inserted into since it was last flushed
+    dirty: bool,
+}
+
+
+/// What `FilterSet::stats` says about one filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterStats {
+    pub path: PathBuf,
+    pub m: NonZeroUsize,
+    pub num_hashes: u32,
+    pub bits_set: usize,
+    /// Inserted into since it was last flushed
+    pub dirty: bool,
+}
+
+
+impl FilterStats {
+    /// Fraction of the bits that are set
+    pub fn fill_ratio(&self) -> f64 {
+        self.bits_set as f64 / usize::from(self.m) as f64
+    }
+
+
+    /// Estimate of how many distinct items have been inserted, from how
+    /// many bits are set (Swamidass & Baldi)
+    pub fn estimated_items(&self) -> f64 {
+        let m = usize::from(self.m) as f64;
+        -(m / self.num_hashes as f64) * (1.0 - self.fill_ratio()).ln()
+    }
+}
+
+
+/// All the filters a server holds, by canonical path
+#[derive(Debug)]
+pub struct FilterSet {
+    filters: HashMap<PathBuf, Mutex<HeldFilter>>,
+    /// Taken before any filter's lock when both are needed
+    wal: Option<Mutex<Wal>>,
+    /// Inserts are refused once this is set, so a last flush gets them all
+    closed: AtomicBool,
+}
+
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+
+impl FilterSet {
+    /// Read each of `filter_filenames` into memory
+    pub fn load(filter_filenames: &[String]) -> Result<FilterSet, String> {
+        let mut filters = HashMap::new();
+        for filename in filter_filenames {
+            let path = match fs::canonicalize(filename) {
+                Ok(path) => path,
+                Err(err) => {
+                    return Err(format!("Cannot access '{}' ({})", filename, err));
+                },
+            };
+            let _lock = match lock::lock(&path, false, true) {
+                Ok(filter_lock) => filter_lock,
+                Err(err) => {
+                    return Err(format!("Unable to lock '{}' ({})", filename, err));
+                },
+            };
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) => {
+                    return Err(format!("Cannot open '{}' ({})", filename, err));
+                },
+            };
+            let (header, bits) = format::read_filter(&file)
+                .map_err(|err| format!("'{}': {}", filename, err))?;
+            /* Flushing ORs bits together, which only makes sense for plain
+             * filters */
+            if header.kind != Kind::Plain {
+                return Err(format!(
+                    "'{}' is a {} filter, which can't be served",
+                    filename,
+                    header.kind.name()
+                ));
+            }
+            filters.insert(path, Mutex::new(HeldFilter {
+                m: header.m,
+                normalize: header.normalize,
+                bits,
+                dirty: false,
+            }));
+        }
+        Ok(FilterSet {filters, wal: None, closed: AtomicBool::new(false)})
+    }
+
+
+    /// Replay the write-ahead log at `wal_path`, creating it if need be, and
+    /// log every insert to it from now on.  Returns how many inserts were
+    /// replayed.  Logged inserts into filters that aren't being served this
+    /// time go straight into their files, since a flush will empty the log.
+    pub fn log_to(&mut self, wal_path: &Path) -> Result<usize, String> {
+        let (wal, inserts) = Wal::open(wal_path)?;
+        let mut replayed = 0;
+        let mut unserved: HashMap<PathBuf, Vec<ItemHashes>> = HashMap::new();
+        for (path, hashes) in inserts {
+            match self.filters.get(&path) {
+                Some(held) => {
+                    let mut held = lock(held);
+                    let m = held.m;
+                    filter_insert_hashes(&hashes, &mut held.bits, m)?;
+                    held.dirty = true;
+                },
+                None => unserved.entry(path).or_default().push(hashes),
+            }
+            replayed += 1;
+        }
+        for (path, all_hashes) in unserved {
+            if let Err(err) = insert_on_disk(&path, &all_hashes) {
+                return Err(format!(
+                    "Log '{}' has inserts into '{}', which isn't being served and couldn't be written to ({})",
+                    wal_path.display(),
+                    path.display(),
+                    err
+                ));
+            }
+        }
+        self.wal = Some(Mutex::new(wal));
+        Ok(replayed)
+    }
+
+
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.filters.keys()
+    }
+
+
+    pub fn stats(&self) -> Vec<FilterStats> {
+        let mut stats: Vec<FilterStats> = self.filters.iter()
+            .map(|(path, held)| held_stats(path, &lock(held)))
+            .collect();
+        stats.sort_by(|a, b| a.path.cmp(&b.path));
+        stats
+    }
+
+
+    pub fn stats_for(&self, filter_filename: &str) -> Result<FilterStats, String> {
+        let path = fs::canonicalize(filter_filename)
+            .unwrap_or_else(|_| PathBuf::from(filter_filename));
+        Ok(held_stats(&path, &lock(self.get(filter_filename)?.1)))
+    }
+
+
+    /// Is the filter at `filter_filename` one of these?
+    pub fn serves(&self, filter_filename: &str) -> bool {
+        self.get(filter_filename).is_ok()
+    }
+
+
+    /// The canonical path of the filter at `filter_filename` and the filter
+    fn get(&self, filter_filename: &str) -> Result<(&PathBuf, &Mutex<HeldFilter>), String> {
+        let path = fs::canonicalize(filter_filename)
+            .unwrap_or_else(|_| PathBuf::from(filter_filename));
+        match self.filters.get_key_value(&path) {
+            Some(entry) => Ok(entry),
+            None => Err(format!("Not serving a filter at '{}'", filter_filename)),
+        }
+    }
+
+
+    /// Insert an item, returning whether it's new, i.e. whether it wasn't
+    /// (probably) in the filter already.  If there's a log, the insert is
+    /// in it by the time this returns.
+    pub fn insert(&self, filter_filename: &str, hashes: &ItemHashes) -> Result<bool, String> {
+        let (path, held) = self.get(filter_filename)?;
+        let mut wal = self.wal.as_ref().map(lock);
+        let mut held = lock(held);
+        /* Checked under the filter's lock, so once `close` has returned no
+        *  insert can land after a flush has been past this filter */
+        if self.closed.load(Ordering::SeqCst) {
+            return Err("Shutting down, not taking inserts".to_owned());
+        }
+        if let Some(wal) = wal.as_mut() {
+            wal.append(path, hashes)?;
+        }
+        let m = held.m;
+        let was_in = test_and_insert_hashes(hashes, &mut held.bits, m)?;
+        held.dirty = true;
+        Ok(!was_in)
+    }
+
+
+    /// The hashes of the item `bytes`, normalized as the filter at
+    /// `filter_filename` says, for front ends that are sent whole items
+    pub fn item_hashes(&self, filter_filename: &str, bytes: &[u8]) -> Result<ItemHashes, String> {
+        let normalize = lock(self.get(filter_filename)?.1).normalize;
+        Ok(item_hashes(&normalize.apply(bytes.to_vec())))
+    }
+
+
+    pub fn query(&self, filter_filename: &str, hashes: &ItemHashes) -> Result<bool, String> {
+        let held = lock(self.get(filter_filename)?.1);
+        hashes_in_filter(hashes, &held.bits, held.m)
+    }
+
+
+    /// Refuse inserts from now on, ahead of a last flush
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+
+    /// Write every changed filter back to disk, then empty the log if they
+    /// all made it.  Carries on past failures and reports the first.
+    pub fn flush(&self) -> Result<(), String> {
+        /* Hold the log throughout so no insert lands between a filter being
+        *  flushed and the log being emptied */
+        let wal = self.wal.as_ref().map(lock);
+        let mut to_return = Ok(());
+        for (path, held) in self.filters.iter() {
+            let mut held = lock(held);
+            let result = if held.dirty {
+                flush_filter(path, &mut held)
+            }
+            else {
+                refresh_filter(path, &mut held)
+            };
+            if let Err(err) = result {
+                if to_return.is_ok() {
+                    to_return = Err(format!("'{}': {}", path.display(), err));
+                }
+            }
+        }
+        if let (Ok(()), Some(mut wal)) = (&to_return, wal) {
+            wal.clear()?;
+        }
+        to_return
+    }
+}
+
+
+fn held_stats(path: &Path, held: &HeldFilter) -> FilterStats {
+    FilterStats {
+        path: path.to_path_buf(),
+        m: held.m,
+        num_hashes: NUM_HASHES,
+        bits_set: held.bits.iter().map(|int| int.count_ones() as usize).sum(),
+        dirty: held.dirty,
+    }
+}
+
+
+/// Merge `held` into the filter on disk at `path` and write the result back
+fn flush_filter(path: &Path, held: &mut HeldFilter) -> Result<(), String> {
+    let _lock = lock::lock(path, true, true).map_err(|err| format!("Unable to lock ({})", err))?;
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let header = merge_from_disk(&file, held)?;
+    format::replace_filter(path, &header, &held.bits)?;
+    held.dirty = false;
+    Ok(())
+}
+
+
+/// Merge whatever's been inserted into the filter on disk at `path` into
+/// `held`, without writing anything
+fn refresh_filter(path: &Path, held: &mut HeldFilter) -> Result<(), String> {
+    let _lock = lock::lock(path, false, true).map_err(|err| format!("Unable to lock ({})", err))?;
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    merge_from_disk(&file, held)?;
+    Ok(())
+}
+
+
+/// Insert every item in `all_hashes` into the filter on disk at `path`,
+/// whatever kind it is
+fn insert_on_disk(path: &Path, all_hashes: &[ItemHashes]) -> Result<(), String> {
+    let _lock = lock::lock(path, true, true).map_err(|err| format!("Unable to lock ({})", err))?;
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let (mut header, mut filter) = format::read_filter(&file)?;
+    let now = unix_now();
+    for hashes in all_hashes {
+        header.kind.test_and_insert(hashes, &mut filter, header.m, now, None)?;
+    }
+    format::replace_filter(path, &header, &filter)
+}
+
+
+/// OR the bits of the filter in (locked) `file` into `held`, returning its
+/// header
+fn merge_from_disk(file: &File, held: &mut HeldFilter) -> Result<format::Header, String> {
+    let (header, on_disk) = format::read_filter(file)?;
+    if header.m != held.m {
+        return Err(format!(
+            "Filter on disk now has m = {} instead of {}",
+            header.m,
+            held.m
+        ));
+    }
+    for (held_int, disk_int) in held.bits.iter_mut().zip(on_disk) {
+        *held_int |= disk_int;
+    }
+    Ok(header)
+}
+
+
+pub fn format_hashes(hashes: &ItemHashes) -> String {
+    hashes.iter().map(|hash| format!("{:08x}", hash)).collect()
+}
+
+
+pub fn parse_hashes(hex: &str) -> Result<ItemHashes, String> {
+    if hex.len() != NUM_HASHES as usize * 8 || !hex.is_ascii() {
+        return Err(format!("Expected {} hex digits of hashes", NUM_HASHES * 8));
+    }
+    let mut hashes = [0; NUM_HASHES as usize];
+    for (i, hash) in hashes.iter_mut().enumerate() {
+        *hash = match u32::from_str_radix(&hex[i * 8..i * 8 + 8], 16) {
+            Ok(hash) => hash,
+            Err(err) => {
+                return Err(format!("Bad hash '{}' ({})", &hex[i * 8..i * 8 + 8], err));
+            },
+        };
+    }
+    Ok(hashes)
+}
+
+
+/// The response line (without a newline) to one request line
+pub fn respond(filters: &FilterSet, request: &str) -> String {
+    let (command, rest) = request.split_once(' ').unwrap_or((request, ""));
+    let result = match command {
+        "PING" => Ok("PONG".to_owned()),
+        "FLUSH" => filters.flush().map(|()| "OK".to_owned()),
+        "QUERY" | "INSERT" | "ADD" => {
+            match rest.split_once(' ') {
+                Some((_, filter_filename)) if !filters.serves(filter_filename) => {
+                    Ok("NOT SERVED".to_owned())
+                },
+                Some((hex, filter_filename)) => {
+                    parse_hashes(hex).and_then(|hashes| {
+                        if command == "QUERY" {
+                            filters.query(filter_filename, &hashes).map(|is_in| {
+                                if is_in {"IN".to_owned()} else {"NOT IN".to_owned()}
+                            })
+                        }
+                        else {
+                            filters.insert(filter_filename, &hashes).map(|is_new| {
+                                match (command, is_new) {
+                                    ("ADD", true) => "ADDED".to_owned(),
+                                    ("ADD", false) => "ALREADY IN".to_owned(),
+                                    _ => "OK".to_owned(),
+                                }
+                            })
+                        }
+                    })
+                },
+                None => Err(format!("Usage: {} <hashes> <filter>", command)),
+            }
+        },
+        _ => Err(format!("Unknown command '{}'", command)),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(err) => format!("ERROR {}", err.replace('\n', " ")),
+    }
+}
+
+
+fn handle_connection(stream: UnixStream, filters: &FilterSet) {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    loop {
+        let mut line = Vec::new();
+        match (&mut reader).take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => {
+                return;
+            },
+            Ok(_) => {},
+        }
+        /* What's left of an overlong line can't be told from a request, so
+        *  that's the end of the connection */
+        if line.len() > MAX_LINE_LEN {
+            let _ = writeln!(writer, "ERROR Request is over {} bytes", MAX_LINE_LEN);
+            return;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let response = match String::from_utf8(line) {
+            Ok(line) => respond(filters, &line),
+            Err(_) => "ERROR Request isn't UTF-8".to_owned(),
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+
+/// Send one request line to the server listening on `socket_path` and
+/// return its response line, or None if there's no server to ask.  A server
+/// that went away or took over ASK_TIMEOUT to answer is an error, since it
+/// may have acted on the request anyway.
+pub fn ask(socket_path: &Path, request: &str) -> Result<Option<String>, String> {
+    /* Connecting to a Unix socket doesn't wait on the server, it fails
+    *  straight away if nothing's listening */
+    let stream = match UnixStream::connect(socket_path) {
+        Ok(stream) => stream,
+        Err(_) => {
+            return Ok(None);
+        },
+    };
+    let no_answer = |err: std::io::Error| format!(
+        "No answer from the server at '{}' ({})",
+        socket_path.display(),
+        err
+    );
+    stream.set_read_timeout(Some(ASK_TIMEOUT)).map_err(no_answer)?;
+    stream.set_write_timeout(Some(ASK_TIMEOUT)).map_err(no_answer)?;
+    let mut writer = &stream;
+    writeln!(writer, "{}", request).map_err(no_answer)?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).map_err(no_answer)?;
+    if !response.ends_with('\n') {
+        return Err(format!("The server at '{}' went away without answering", socket_path.display()));
+    }
+    response.pop();
+    Ok(Some(response))
+}
+
+
+/// Start listening on `socket_path`, clearing away the socket of a server
+/// that's no longer running if need be.
+pub fn bind(socket_path: &Path) -> Result<UnixListener, String> {
+    if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("'{}' exists and isn't a socket", socket_path.display()));
+        }
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(format!("Something is already listening on '{}'", socket_path.display()));
+        }
+        if let Err(err) = fs::remove_file(socket_path) {
+            return Err(format!("Unable to remove stale '{}' ({})", socket_path.display(), err));
+        }
+    }
+    UnixListener::bind(socket_path).map_err(|err| {
+        format!("Unable to listen on '{}' ({})", socket_path.display(), err)
+    })
+}
+
+
+/// Flush changed filters every `flush_interval` from now on
+pub fn flush_every(filters: Arc<FilterSet>, flush_interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(flush_interval);
+            if let Err(err) = filters.flush() {
+                eprintln!("ERROR: {}", err);
+            }
+        }
+    });
+}
+
+
+/// Answer requests on `listener` forever
+pub fn serve(listener: UnixListener, filters: Arc<FilterSet>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let filters = Arc::clone(&filters);
+                thread::spawn(move || handle_connection(stream, &filters));
+            },
+            Err(err) => {
+                eprintln!("ERROR: {}", err);
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+
+    fn temp_filter(name: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-server-{}-{}", name, std::process::id()));
+        let m = NonZeroUsize::new(4096).unwrap();
+        assert!(format::write_filter(File::create(&path).unwrap(), m, &[0; 64]).is_ok());
+        path.to_str().unwrap().to_owned()
+    }
+
+    /* The filter and the lock file taking a lock on it leaves */
+    fn remove_filter(filter: &str) {
+        let _ = fs::remove_file(lock::lock_path(Path::new(filter)));
+        fs::remove_file(filter).unwrap();
+    }
+
+    #[test]
+    fn test_hashes_round_trip() {
+        let hashes = item_hashes(b"known");
+        let hex = format_hashes(&hashes);
+        assert_eq!(hex.len(), 64);
+        assert_eq!(&hex[..8], "468c1b4e");
+        assert_eq!(parse_hashes(&hex), Ok(hashes));
+        assert!(parse_hashes(&hex[1..]).is_err());
+        assert!(parse_hashes(&hex.replace('4', "g")).is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let filter = temp_filter("respond");
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        let known = format_hashes(&item_hashes(b"known"));
+
+        assert_eq!(respond(&filters, "PING"), "PONG");
+        assert_eq!(respond(&filters, &format!("QUERY {} {}", known, filter)), "NOT IN");
+        assert_eq!(respond(&filters, &format!("INSERT {} {}", known, filter)), "OK");
+        assert_eq!(respond(&filters, &format!("QUERY {} {}", known, filter)), "IN");
+        assert_eq!(respond(&filters, &format!("ADD {} {}", known, filter)), "ALREADY IN");
+        let other = format_hashes(&item_hashes(b"other"));
+        assert_eq!(respond(&filters, &format!("ADD {} {}", other, filter)), "ADDED");
+        assert_eq!(respond(&filters, &format!("ADD {} /nope", other)), "NOT SERVED");
+        assert_eq!(respond(&filters, &format!("QUERY {} /nope", known)), "NOT SERVED");
+        assert_eq!(respond(&filters, &format!("INSERT {} /nope", known)), "NOT SERVED");
+        assert!(respond(&filters, &format!("QUERY 123 {}", filter)).starts_with("ERROR "));
+        assert!(respond(&filters, "QUERY").starts_with("ERROR "));
+        assert!(respond(&filters, "DANCE").starts_with("ERROR "));
+
+        /* Nothing on disk until a flush */
+        let (_, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert!(!hashes_in_filter(&item_hashes(b"known"), &bits, NonZeroUsize::new(4096).unwrap()).unwrap());
+        assert_eq!(respond(&filters, "FLUSH"), "OK");
+        let (_, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert!(hashes_in_filter(&item_hashes(b"known"), &bits, NonZeroUsize::new(4096).unwrap()).unwrap());
+
+        /* Still answering queries once closed, but no more inserts */
+        filters.close();
+        let late = format_hashes(&item_hashes(b"late"));
+        assert!(respond(&filters, &format!("INSERT {} {}", late, filter)).starts_with("ERROR "));
+        assert!(respond(&filters, &format!("ADD {} {}", late, filter)).starts_with("ERROR "));
+        assert_eq!(respond(&filters, &format!("QUERY {} {}", known, filter)), "IN");
+        assert_eq!(respond(&filters, &format!("QUERY {} {}", late, filter)), "NOT IN");
+
+        remove_filter(&filter);
+    }
+
+    #[test]
+    fn test_normalized_filter() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-server-normalized-{}", std::process::id()));
+        let m = NonZeroUsize::new(4096).unwrap();
+        let normalize: Normalize = "line-endings,case".parse().unwrap();
+        assert!(format::write_normalized_filter(File::create(&path).unwrap(), m, normalize, &[0; 64]).is_ok());
+        let filter = path.to_str().unwrap().to_owned();
+
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        let hashes = filters.item_hashes(&filter, b"Known\r\n").unwrap();
+        assert_eq!(hashes, item_hashes(b"known\n"));
+        assert!(filters.item_hashes("/nope", b"known").is_err());
+
+        /* Flushes keep the header's normalizers */
+        assert_eq!(filters.insert(&filter, &hashes), Ok(true));
+        assert!(filters.flush().is_ok());
+        let (header, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert_eq!(header.normalize, normalize);
+        assert!(crate::is_in_filter(b"known\n", &bits, m).unwrap());
+
+        remove_filter(&filter);
+    }
+
+    #[test]
+    fn test_flush_merges_with_disk() {
+        let filter = temp_filter("merge");
+        let m = NonZeroUsize::new(4096).unwrap();
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        assert_eq!(filters.insert(&filter, &item_hashes(b"via server")), Ok(true));
+        assert_eq!(filters.insert(&filter, &item_hashes(b"via server")), Ok(false));
+
+        /* Someone else inserts straight into the file meanwhile */
+        let mut bits = vec![0; 64];
+        assert!(crate::filter_insert(b"direct", &mut bits, m).is_ok());
+        assert!(format::write_filter(File::create(&filter).unwrap(), m, &bits).is_ok());
+
+        assert!(filters.flush().is_ok());
+        let (_, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert!(crate::is_in_filter(b"via server", &bits, m).unwrap());
+        assert!(crate::is_in_filter(b"direct", &bits, m).unwrap());
+        assert!(filters.query(&filter, &item_hashes(b"direct")).unwrap());
+
+        let stats = filters.stats();
+        assert_eq!(stats.len(), 1);
+        assert!(!stats[0].dirty);
+        assert_eq!(stats[0].m, m);
+        assert!(stats[0].bits_set > 8 && stats[0].bits_set <= 16);
+        assert!((stats[0].estimated_items() - 2.0).abs() < 0.5);
+
+        remove_filter(&filter);
+    }
+
+    #[test]
+    fn test_flush_reads_back_direct_inserts() {
+        let filter = temp_filter("reread");
+        let m = NonZeroUsize::new(4096).unwrap();
+        let filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+
+        let mut bits = vec![0; 64];
+        assert!(crate::filter_insert(b"direct", &mut bits, m).is_ok());
+        assert!(format::write_filter(File::create(&filter).unwrap(), m, &bits).is_ok());
+        assert!(!filters.query(&filter, &item_hashes(b"direct")).unwrap());
+
+        /* Read back, not written */
+        assert!(filters.flush().is_ok());
+        assert!(filters.query(&filter, &item_hashes(b"direct")).unwrap());
+        assert!(!filters.stats()[0].dirty);
+        assert_eq!(format::read_filter(File::open(&filter).unwrap()).unwrap().1, bits);
+
+        remove_filter(&filter);
+    }
+
+    #[test]
+    fn test_wal_replay() {
+        let filter = temp_filter("wal");
+        let mut wal_path = std::env::temp_dir();
+        wal_path.push(format!("bloom-cli-server-{}.wal", std::process::id()));
+        let _ = fs::remove_file(&wal_path);
+
+        /* A server that's killed before it flushes */
+        {
+            let mut filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+            assert_eq!(filters.log_to(&wal_path), Ok(0));
+            assert_eq!(filters.insert(&filter, &item_hashes(b"logged")), Ok(true));
+        }
+
+        let mut filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        assert!(!filters.query(&filter, &item_hashes(b"logged")).unwrap());
+        assert_eq!(filters.log_to(&wal_path), Ok(1));
+        assert!(filters.query(&filter, &item_hashes(b"logged")).unwrap());
+        assert!(filters.stats()[0].dirty);
+
+        assert!(filters.flush().is_ok());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        let (_, bits) = format::read_filter(File::open(&filter).unwrap()).unwrap();
+        assert!(hashes_in_filter(&item_hashes(b"logged"), &bits, NonZeroUsize::new(4096).unwrap()).unwrap());
+        drop(filters);
+
+        /* Logged inserts into a filter that's no longer served go straight
+         * into its file */
+        let other = temp_filter("wal-other");
+        {
+            let mut filters = FilterSet::load(&[filter.clone(), other.clone()]).unwrap();
+            assert_eq!(filters.log_to(&wal_path), Ok(0));
+            assert_eq!(filters.insert(&other, &item_hashes(b"unserved")), Ok(true));
+        }
+        let mut filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        assert_eq!(filters.log_to(&wal_path), Ok(1));
+        assert!(filters.flush().is_ok());
+        let (_, bits) = format::read_filter(File::open(&other).unwrap()).unwrap();
+        assert!(hashes_in_filter(&item_hashes(b"unserved"), &bits, NonZeroUsize::new(4096).unwrap()).unwrap());
+        drop(filters);
+
+        /* ...and if that can't be done the log's kept and the server doesn't
+         * start */
+        {
+            let mut filters = FilterSet::load(&[filter.clone(), other.clone()]).unwrap();
+            assert_eq!(filters.log_to(&wal_path), Ok(0));
+            assert_eq!(filters.insert(&other, &item_hashes(b"lost")), Ok(true));
+        }
+        remove_filter(&other);
+        let mut filters = FilterSet::load(std::slice::from_ref(&filter)).unwrap();
+        assert!(filters.log_to(&wal_path).unwrap_err().contains("isn't being served"));
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+        fs::remove_file(lock::lock_path(Path::new(&other))).unwrap();
+        remove_filter(&filter);
+        fs::remove_file(&wal_path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_replaces_whole() {
+        let filter = temp_filter("replace");
+        let filters = Arc::new(FilterSet::load(std::slice::from_ref(&filter)).unwrap());
+        let flushing = {
+            let filters = Arc::clone(&filters);
+            let filter = filter.clone();
+            thread::spawn(move || {
+                for i in 0..50u32 {
+                    filters.insert(&filter, &item_hashes(&i.to_be_bytes())).unwrap();
+                    filters.flush().unwrap();
+                }
+            })
+        };
+
+        /* Read without the lock, as after a crash, it's always one whole
+         * filter or another */
+        while !flushing.is_finished() {
+            assert!(format::verify_filter(&fs::read(&filter).unwrap()).is_ok());
+        }
+        flushing.join().unwrap();
+        remove_filter(&filter);
+    }
+
+    #[test]
+    fn test_serve_over_socket() {
+        let filter = temp_filter("socket");
+        let mut socket_path = std::env::temp_dir();
+        socket_path.push(format!("bloom-cli-server-{}.sock", std::process::id()));
+        let filters = Arc::new(FilterSet::load(std::slice::from_ref(&filter)).unwrap());
+        let listener = bind(&socket_path).unwrap();
+        assert!(bind(&socket_path).is_err());
+        thread::spawn(move || serve(listener, filters));
+
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        let mut writer = &stream;
+        let known = format_hashes(&item_hashes(b"known"));
+        writeln!(writer, "INSERT {} {}", known, filter).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "OK");
+        writeln!(writer, "QUERY {} {}", known, filter).unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "IN");
+
+        /* One request per connection */
+        let unknown = format_hashes(&item_hashes(b"unknown"));
+        assert_eq!(
+            ask(&socket_path, &format!("QUERY {} {}", unknown, filter)),
+            Ok(Some("NOT IN".to_owned()))
+        );
+        assert_eq!(ask(&socket_path, "PING"), Ok(Some("PONG".to_owned())));
+
+        /* An overlong request gets an error and the connection's closed */
+        let stream = UnixStream::connect(&socket_path).unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        let mut writer = &stream;
+        writeln!(writer, "PING {}", "a".repeat(MAX_LINE_LEN)).unwrap();
+        assert!(lines.next().unwrap().unwrap().starts_with("ERROR Request is over"));
+        assert!(lines.next().is_none());
+
+        remove_filter(&filter);
+        fs::remove_file(&socket_path).unwrap();
+        assert_eq!(ask(&socket_path, "PING"), Ok(None));
+
+        /* A server that hangs up without answering is an error, not the same
+         * as no server */
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        thread::spawn(move || drop(listener.accept()));
+        assert!(ask(&socket_path, "PING").is_err());
+        fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+/* Stable Bloom filters, for streams that never end
+*
+*  Given enough items, a plain filter ends up with every bit set and then
+*  everything's "in" it.  A stable Bloom filter (Deng & Rafiei, 2006) has
+*  small counters where a plain one has bits.  Inserting an item first
+*  decrements `decrements` counters picked at random, then sets the item's
+*  own counters to `max`, and an item's in the filter if none of its
+*  counters are 0.  Older items fade out as their counters are worn down, so
+*  the fraction of counters at 0 settles, and the false positive rate with
+*  it, however many items go in.  The price is that items seen long enough
+*  ago can be reported as not in.
+*
+*  Counters are 4 bits, 16 to a u64, the first in the top bits, so `max` can
+*  be up to 15.  The counters to decrement are picked by a generator whose
+*  state is kept in the header, so the same inserts always make the same
+*  filter.
+*/
+use std::fmt;
+use std::num::NonZeroUsize;
+
+use crate::ItemHashes;
+
+pub const MAX_CELL_MAX: u8 = 15;
+pub const DEFAULT_CELL_MAX: u8 = 7;
+const CELL_BITS: usize = 4;
+const CELLS_PER_U64: usize = 64 / CELL_BITS;
+
+
+/// How a stable filter's counters decay, and where its generator is up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stability {
+    /// What an item's counters are set to when it's inserted
+    pub max: u8,
+    /// How many counters are decremented per insert
+    pub decrements: u32,
+    /// State of the generator picking counters to decrement
+    pub state: u64,
+}
+
+
+impl fmt::Display for Stability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stable, counters up to {}, {} decrements per insert", self.max, self.decrements)
+    }
+}
+
+
+impl Stability {
+    /// Settings for a stable filter of `m` counters
+    pub fn new(max: u8, decrements: u32, state: u64, m: NonZeroUsize) -> Result<Stability, String> {
+        if max == 0 || max > MAX_CELL_MAX {
+            return Err(format!("A stable filter's counters go up to 1 to {}", MAX_CELL_MAX));
+        }
+        if decrements == 0 {
+            return Err("A stable filter needs at least 1 decrement per insert".to_owned());
+        }
+        if decrements as usize > usize::from(m) {
+            return Err(format!("A stable filter can't have more decrements per insert than its {} counters", m));
+        }
+        Ok(Stability {max, decrements, state})
+    }
+
+
+    /// How many u64s the counters of a filter with `m` of them take up
+    pub fn body_u64s(m: NonZeroUsize) -> usize {
+        (usize::from(m) - 1) / CELLS_PER_U64 + 1
+    }
+
+
+    /// Is an item with `hashes` (probably) in `filter`?
+    pub fn contains(&self, hashes: &ItemHashes, filter: &[u64], m: NonZeroUsize) -> Result<bool, String> {
+        for hash in hashes.iter() {
+            if counter(filter, *hash as usize % usize::from(m))? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+
+    /// Wear down some counters and insert an item with `hashes`, returning
+    /// whether it was (probably) in already
+    pub fn test_and_insert(&mut self, hashes: &ItemHashes, filter: &mut [u64], m: NonZeroUsize) -> Result<bool, String> {
+        let was_in = self.contains(hashes, filter, m)?;
+        for _ in 0..self.decrements {
+            let index = (self.next_random() % usize::from(m) as u64) as usize;
+            let count = counter(filter, index)?;
+            if count > 0 {
+                set_counter(filter, index, count - 1)?;
+            }
+        }
+        for hash in hashes.iter() {
+            set_counter(filter, *hash as usize % usize::from(m), self.max)?;
+        }
+        Ok(was_in)
+    }
+
+
+    /* splitmix64 */
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+
+fn counter(filter: &[u64], index: usize) -> Result<u8, String> {
+    match filter.get(index / CELLS_PER_U64) {
+        Some(int) => Ok(((int >> shift(index)) & 0xf) as u8),
+        None => Err(format!("Filter has {} u64s, too few for counter {}", filter.len(), index)),
+    }
+}
+
+
+fn set_counter(filter: &mut [u64], index: usize, count: u8) -> Result<(), String> {
+    let len = filter.len();
+    match filter.get_mut(index / CELLS_PER_U64) {
+        Some(int) => {
+            *int = (*int & !(0xf << shift(index))) | ((count as u64 & 0xf) << shift(index));
+            Ok(())
+        },
+        None => Err(format!("Filter has {} u64s, too few for counter {}", len, index)),
+    }
+}
+
+
+/* Where counter `index` is in its u64 */
+fn shift(index: usize) -> usize {
+    64 - CELL_BITS * (index % CELLS_PER_U64 + 1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+
+    #[test]
+    fn test_counters() {
+        let mut filter = vec![0; 2];
+        assert!(set_counter(&mut filter, 0, 15).is_ok());
+        assert!(set_counter(&mut filter, 17, 3).is_ok());
+        assert_eq!(filter, vec![0xf000000000000000, 0x0300000000000000]);
+        assert_eq!(counter(&filter, 0), Ok(15));
+        assert_eq!(counter(&filter, 1), Ok(0));
+        assert_eq!(counter(&filter, 17), Ok(3));
+        assert!(set_counter(&mut filter, 31, 1).is_ok());
+        assert_eq!(filter[1], 0x0300000000000001);
+        assert!(counter(&filter, 32).is_err());
+        assert!(set_counter(&mut filter, 32, 1).is_err());
+        assert_eq!(Stability::body_u64s(NonZeroUsize::new(16).unwrap()), 1);
+        assert_eq!(Stability::body_u64s(NonZeroUsize::new(17).unwrap()), 2);
+    }
+
+    #[test]
+    fn test_stable_filter() {
+        let m = NonZeroUsize::new(10_000).unwrap();
+        let mut stability = Stability::new(3, 10, 0, m).unwrap();
+        let mut filter = vec![0; Stability::body_u64s(m)];
+        let known = item_hashes(b"known");
+        assert_eq!(stability.test_and_insert(&known, &mut filter, m), Ok(false));
+        assert_eq!(stability.contains(&known, &filter, m), Ok(true));
+        assert_eq!(stability.test_and_insert(&known, &mut filter, m), Ok(true));
+
+        /* Far more items than a plain filter this size could take */
+        for i in 0..100_000 {
+            let hashes = item_hashes(i.to_string().as_bytes());
+            assert!(stability.test_and_insert(&hashes, &mut filter, m).is_ok());
+            assert_eq!(stability.contains(&hashes, &filter, m), Ok(true));
+        }
+        let false_positives = (100_000..110_000)
+            .filter(|i| stability.contains(&item_hashes(i.to_string().as_bytes()), &filter, m).unwrap())
+            .count();
+        /* Deng & Rafiei put the fraction of counters left at 0 at about
+        *  0.17 for these settings, so about (1 - 0.17)^8 = 0.22 of items
+        *  never inserted look in */
+        assert!(false_positives < 2600, "{} false positives", false_positives);
+        /* Long forgotten */
+        assert_eq!(stability.contains(&known, &filter, m), Ok(false));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let m = NonZeroUsize::new(1000).unwrap();
+        let mut a = Stability::new(DEFAULT_CELL_MAX, 5, 42, m).unwrap();
+        let mut b = a;
+        let mut filter_a = vec![0; Stability::body_u64s(m)];
+        let mut filter_b = filter_a.clone();
+        for i in 0..100 {
+            let hashes = item_hashes(i.to_string().as_bytes());
+            assert!(a.test_and_insert(&hashes, &mut filter_a, m).is_ok());
+            assert!(b.test_and_insert(&hashes, &mut filter_b, m).is_ok());
+        }
+        assert_eq!((a, filter_a), (b, filter_b));
+        assert!(Stability::new(0, 5, 0, m).is_err());
+        assert!(Stability::new(MAX_CELL_MAX + 1, 5, 0, m).is_err());
+        assert!(Stability::new(3, 0, 0, m).is_err());
+        assert!(Stability::new(3, 1000, 0, m).is_ok());
+        assert!(Stability::new(3, 1001, 0, m).is_err());
+        assert!(Stability::new(3, u32::MAX, 0, m).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+/* Views of files: what of a file actually goes into a filter
+*
+*  By default that's a file's whole contents, but a filter can instead be
+*  built from each file's SHA-256 digest.  Digests can also come from a
+*  digest list, like `sha256sum` prints, when there are no files to hand, and
+*  land on the same bits as the files they're of.
+*
+*  A view can also be of a file's metadata, so that asking whether an exact
+*  version of a file has been seen doesn't mean reading it.  The fields
+*  chosen are encoded in a fixed order, whatever order they're given in, as
+*
+*    <field>=<value>\0
+*
+*  with the contents, if chosen, last and unterminated.  A view of only the
+*  contents, only the digest or only the git object ID is just those bytes.
+*/
+use sha2::Digest;
+use sha2::Sha256;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::git;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    /// The path the file was given by, relative to the current directory if
+    /// it's under it
+    Path,
+    /// Size in bytes
+    Size,
+    /// Modification time, to the nanosecond
+    Mtime,
+    /// Permission bits
+    Mode,
+    /// Owning user and group ids
+    Owner,
+    /// Device and inode numbers, which tell hard links to a file from
+    /// copies of it
+    Inode,
+    /// The file's SHA-256 digest
+    Sha256,
+    /// The object ID git would give the file's contents as a blob
+    GitOid,
+    /// The whole file
+    Contents,
+}
+
+
+/* The order fields are encoded in */
+const FIELDS: [Field; 9] = [
+    Field::Path,
+    Field::Size,
+    Field::Mtime,
+    Field::Mode,
+    Field::Owner,
+    Field::Inode,
+    Field::Sha256,
+    Field::GitOid,
+    Field::Contents,
+];
+
+
+impl Field {
+    fn name(&self) -> &'static str {
+        match self {
+            Field::Path => "path",
+            Field::Size => "size",
+            Field::Mtime => "mtime",
+            Field::Mode => "mode",
+            Field::Owner => "owner",
+            Field::Inode => "inode",
+            Field::Sha256 => "sha256",
+            Field::GitOid => "git-oid",
+            Field::Contents => "contents",
+        }
+    }
+
+
+    fn bit(&self) -> u16 {
+        1 << FIELDS.iter().position(|field| field == self).unwrap_or_default()
+    }
+}
+
+
+/// The fields of a file that go into a filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    fields: u16,
+}
+
+
+impl FromStr for View {
+    type Err = String;
+
+    /// Comma separated field names, like "path,size,mtime"
+    fn from_str(view: &str) -> Result<View, String> {
+        let mut fields = 0;
+        for name in view.split(',') {
+            match FIELDS.iter().find(|field| field.name() == name) {
+                Some(field) => fields |= field.bit(),
+                None => {
+                    let names: Vec<&str> = FIELDS.iter().map(Field::name).collect();
+                    return Err(format!("'{}' isn't one of {}", name, names.join(", ")));
+                },
+            }
+        }
+        Ok(View {fields})
+    }
+}
+
+
+impl View {
+    pub const CONTENTS: View = View {fields: 1 << 8};
+    pub const SHA256: View = View {fields: 1 << 6};
+    pub const GIT_OID: View = View {fields: 1 << 7};
+
+
+    pub fn has(&self, field: Field) -> bool {
+        self.fields & field.bit() != 0
+    }
+
+
+    /// The bytes of the file at `path` that go into a filter
+    pub fn of_file(&self, path: &Path) -> Result<Vec<u8>, String> {
+        if *self == View::CONTENTS {
+            return fs::read(path).map_err(|err| err.to_string());
+        }
+        if *self == View::SHA256 {
+            return sha256_file(path).map(|digest| digest.to_vec()).map_err(|err| err.to_string());
+        }
+        if *self == View::GIT_OID {
+            return git::blob_id(&fs::read(path).map_err(|err| err.to_string())?).map(|id| id.to_vec());
+        }
+
+        let metadata = fs::metadata(path).map_err(|err| err.to_string())?;
+        let mut encoded = Vec::new();
+        for field in FIELDS.iter().filter(|field| self.has(**field)) {
+            encoded.extend_from_slice(field.name().as_bytes());
+            encoded.push(b'=');
+            match field {
+                Field::Path => encoded.extend_from_slice(relative_path(path).as_os_str().as_bytes()),
+                Field::Size => encoded.extend_from_slice(metadata.len().to_string().as_bytes()),
+                Field::Mtime => {
+                    let mtime = format!("{}.{:09}", metadata.mtime(), metadata.mtime_nsec());
+                    encoded.extend_from_slice(mtime.as_bytes());
+                },
+                Field::Mode => encoded.extend_from_slice(format!("{:o}", metadata.mode() & 0o7777).as_bytes()),
+                Field::Owner => {
+                    encoded.extend_from_slice(format!("{}:{}", metadata.uid(), metadata.gid()).as_bytes());
+                },
+                Field::Inode => {
+                    encoded.extend_from_slice(format!("{}:{}", metadata.dev(), metadata.ino()).as_bytes());
+                },
+                Field::Sha256 => {
+                    let digest = sha256_file(path).map_err(|err| err.to_string())?;
+                    encoded.extend(digest.iter().flat_map(|byte| format!("{:02x}", byte).into_bytes()));
+                },
+                Field::GitOid => {
+                    let id = git::blob_id(&fs::read(path).map_err(|err| err.to_string())?)?;
+                    encoded.extend(id.iter().flat_map(|byte| format!("{:02x}", byte).into_bytes()));
+                },
+                Field::Contents => {
+                    encoded.extend(fs::read(path).map_err(|err| err.to_string())?);
+                    continue;
+                },
+            }
+            encoded.push(0);
+        }
+        Ok(encoded)
+    }
+}
+
+
+/* `path` relative to the current directory if it's under it, and without
+ * any leading "./", so a file gets the same path however it's named */
+fn relative_path(path: &Path) -> &Path {
+    let path = match std::env::current_dir() {
+        Ok(cwd) => path.strip_prefix(cwd).unwrap_or(path),
+        Err(_) => path,
+    };
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+
+/// SHA-256 of `bytes`, the same as `sha256_file` of a file holding them
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+
+/// SHA-256 of the file at `path`, without reading it all into memory
+pub fn sha256_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+
+/// The digests in a list like `sha256sum` prints, one per line,
+///
+///   <64 hex digits>  <name>
+///
+/// (or " *<name>" for binary mode, or no name at all), each along with its
+/// name, or the digest in hex if there isn't one.  Blank lines are skipped.
+pub fn parse_digest_list(list: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut digests = Vec::new();
+    for (line_num, line) in list.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (hex, name) = match line.split_once(' ') {
+            Some((hex, name)) => {
+                let name = name.strip_prefix(' ').or_else(|| name.strip_prefix('*')).unwrap_or(name);
+                (hex, name)
+            },
+            None => (line, line),
+        };
+        match decode_digest(hex) {
+            Some(digest) => digests.push((name.to_owned(), digest)),
+            None => {
+                return Err(format!(
+                    "Line {}: expected a SHA-256 digest (64 hex digits), got '{}'",
+                    line_num + 1,
+                    hex
+                ));
+            },
+        }
+    }
+    Ok(digests)
+}
+
+
+fn decode_digest(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* sha256sum of "known" */
+    const KNOWN: &str = "7117fff2d0fd294462b3c802b7cb8753579f23f3946b99cf55f38e873f013f10";
+
+    #[test]
+    fn test_views_agree_with_digest_lists() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-view-{}", std::process::id()));
+        std::fs::write(&path, b"known").unwrap();
+
+        assert_eq!(View::CONTENTS.of_file(&path), Ok(b"known".to_vec()));
+        let digest = View::SHA256.of_file(&path).unwrap();
+        assert_eq!(sha256(b"known").to_vec(), digest);
+        let listed = parse_digest_list(&format!("{}  some/file\n", KNOWN)).unwrap();
+        assert_eq!(listed, vec![("some/file".to_owned(), digest)]);
+        assert!(View::SHA256.of_file(Path::new("/nonexistent")).is_err());
+        let id = View::GIT_OID.of_file(&path).unwrap();
+        assert_eq!(git::blob_id(b"known").unwrap().to_vec(), id);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_digest_list() {
+        let list = format!("{}  a b\r\n\n{} *c\n{}\n", KNOWN, KNOWN.to_uppercase(), KNOWN);
+        let digests = parse_digest_list(&list).unwrap();
+        assert_eq!(digests.len(), 3);
+        assert_eq!(digests[0].0, "a b");
+        assert_eq!(digests[1].0, "c");
+        assert_eq!(digests[2].0, KNOWN);
+        assert!(digests.iter().all(|(_, digest)| *digest == digests[0].1));
+        assert_eq!(digests[0].1[..2], [0x71, 0x17]);
+
+        assert!(parse_digest_list("abc  file\n").is_err());
+        assert!(parse_digest_list(&format!("{}  ok\nnope\n", KNOWN)).unwrap_err().starts_with("Line 2"));
+        assert_eq!(parse_digest_list(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_view_from_str() {
+        assert_eq!("sha256".parse(), Ok(View::SHA256));
+        assert_eq!("contents".parse(), Ok(View::CONTENTS));
+        assert_eq!("git-oid".parse(), Ok(View::GIT_OID));
+        assert_eq!("size,path".parse::<View>(), "path,size".parse::<View>());
+        let view: View = "mtime,contents".parse().unwrap();
+        assert!(view.has(Field::Mtime) && view.has(Field::Contents) && !view.has(Field::Size));
+        assert!("md5".parse::<View>().is_err());
+        assert!("path,".parse::<View>().is_err());
+    }
+
+    #[test]
+    fn test_metadata_views() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("bloom-cli-view-metadata-{}", std::process::id()));
+        std::fs::write(&path, b"known").unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        let then = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 5);
+        file.set_modified(then).unwrap();
+
+        let view: View = "size,mtime".parse().unwrap();
+        assert_eq!(view.of_file(&path), Ok(b"size=5\0mtime=1000000000.000000005\0".to_vec()));
+        let with_contents: View = "contents,size".parse().unwrap();
+        assert_eq!(with_contents.of_file(&path), Ok(b"size=5\0contents=known".to_vec()));
+        let with_digest: View = "sha256,path".parse().unwrap();
+        let encoded = with_digest.of_file(&path).unwrap();
+        assert!(encoded.ends_with(format!("\0sha256={}\0", KNOWN).as_bytes()));
+
+        /* Same contents, different version */
+        file.set_modified(then + std::time::Duration::from_secs(1)).unwrap();
+        assert_ne!(view.of_file(&path), Ok(b"size=5\0mtime=1000000000.000000005\0".to_vec()));
+        assert_eq!(View::CONTENTS.of_file(&path), Ok(b"known".to_vec()));
+        assert!(view.of_file(Path::new("/nonexistent")).is_err());
+
+        /* A hard link is the same file, a copy isn't */
+        let inode: View = "inode".parse().unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let expected = format!("inode={}:{}\0", metadata.dev(), metadata.ino()).into_bytes();
+        assert_eq!(inode.of_file(&path), Ok(expected.clone()));
+        let mut link = path.clone();
+        link.set_extension("link");
+        let mut copy = path.clone();
+        copy.set_extension("copy");
+        let _ = fs::remove_file(&link);
+        fs::hard_link(&path, &link).unwrap();
+        fs::copy(&path, &copy).unwrap();
+        assert_eq!(inode.of_file(&link), Ok(expected.clone()));
+        assert_ne!(inode.of_file(&copy), Ok(expected));
+
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_file(&copy).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(relative_path(&cwd.join("a/b")), Path::new("a/b"));
+        assert_eq!(relative_path(Path::new("./a/b")), Path::new("a/b"));
+        assert_eq!(relative_path(Path::new("/nonexistent/a")), Path::new("/nonexistent/a"));
+    }
+}
@@ -0,0 +1,156 @@
+/* A server's write-ahead log of inserts
+*
+*  Every insert a server acknowledges is first appended here and synced, one
+*  line per item:
+*
+*    <hashes> <filter>
+*
+*  in the same form as the server's INSERT requests.  Once every filter has
+*  been flushed the log is emptied.  A server that died before that replays
+*  the log when it's started again, so nothing it said OK to is lost.  A last
+*  line without a newline is an append that was cut off, whose insert was
+*  never acknowledged, so it's ignored.
+*/
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::server::{format_hashes, parse_hashes};
+use crate::ItemHashes;
+
+
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+}
+
+
+impl Wal {
+    /// Open (or create) the log at `wal_path` and return it along with the
+    /// inserts already in it.  The log is locked for as long as it's open.
+    pub fn open(wal_path: &Path) -> Result<(Wal, Vec<(PathBuf, ItemHashes)>), String> {
+        let mut file = match OpenOptions::new().read(true).append(true).create(true).open(wal_path) {
+            Ok(file) => file,
+            Err(err) => {
+                return Err(format!("Cannot open log '{}' ({})", wal_path.display(), err));
+            },
+        };
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                return Err(format!("Log '{}' is in use by another server", wal_path.display()));
+            },
+            Err(TryLockError::Error(err)) => {
+                return Err(format!("Unable to lock log '{}' ({})", wal_path.display(), err));
+            },
+        }
+
+        let mut contents = String::new();
+        if let Err(err) = file.read_to_string(&mut contents) {
+            return Err(format!("Cannot read log '{}' ({})", wal_path.display(), err));
+        }
+        let complete_len = contents.rfind('\n').map_or(0, |last_newline| last_newline + 1);
+        let complete = &contents[..complete_len];
+
+        let mut inserts = Vec::new();
+        for (line_num, line) in complete.lines().enumerate() {
+            let parsed = match line.split_once(' ') {
+                Some((hex, filter)) => parse_hashes(hex).map(|hashes| (PathBuf::from(filter), hashes)),
+                None => Err("Expected '<hashes> <filter>'".to_owned()),
+            };
+            match parsed {
+                Ok(insert) => inserts.push(insert),
+                Err(err) => {
+                    return Err(format!("Log '{}' line {}: {}", wal_path.display(), line_num + 1, err));
+                },
+            }
+        }
+
+        let mut wal = Wal {file};
+        if complete_len < contents.len() {
+            /* Drop the cut off append so the next one starts on its own line */
+            wal.truncate(complete_len as u64)?;
+        }
+        Ok((wal, inserts))
+    }
+
+
+    fn truncate(&mut self, len: u64) -> Result<(), String> {
+        if let Err(err) = self.file.set_len(len) {
+            return Err(format!("Unable to truncate log ({})", err));
+        }
+        if let Err(err) = self.file.seek(SeekFrom::End(0)) {
+            return Err(err.to_string());
+        }
+        self.file.sync_data().map_err(|err| err.to_string())
+    }
+
+
+    /// Record an insert, returning once it's on disk
+    pub fn append(&mut self, filter_path: &Path, hashes: &ItemHashes) -> Result<(), String> {
+        let line = format!("{} {}\n", format_hashes(hashes), filter_path.display());
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            return Err(format!("Unable to append to log ({})", err));
+        }
+        self.file.sync_data().map_err(|err| format!("Unable to sync log ({})", err))
+    }
+
+
+    /// Forget everything logged so far, once it's all been flushed
+    pub fn clear(&mut self) -> Result<(), String> {
+        self.truncate(0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_hashes;
+    use std::fs;
+
+    #[test]
+    fn test_wal() {
+        let mut wal_path = std::env::temp_dir();
+        wal_path.push(format!("bloom-cli-wal-{}", std::process::id()));
+        let _ = fs::remove_file(&wal_path);
+
+        {
+            let (mut wal, inserts) = Wal::open(&wal_path).unwrap();
+            assert!(inserts.is_empty());
+            assert!(Wal::open(&wal_path).is_err());
+            wal.append(Path::new("/a filter"), &item_hashes(b"one")).unwrap();
+            wal.append(Path::new("/b"), &item_hashes(b"two")).unwrap();
+        }
+
+        /* An append cut off part way through is dropped */
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(b"0123abcd").unwrap();
+        {
+            let (mut wal, inserts) = Wal::open(&wal_path).unwrap();
+            assert_eq!(inserts, vec![
+                (PathBuf::from("/a filter"), item_hashes(b"one")),
+                (PathBuf::from("/b"), item_hashes(b"two")),
+            ]);
+            wal.append(Path::new("/c"), &item_hashes(b"three")).unwrap();
+        }
+        {
+            let (mut wal, inserts) = Wal::open(&wal_path).unwrap();
+            assert_eq!(inserts.len(), 3);
+            assert_eq!(inserts[2], (PathBuf::from("/c"), item_hashes(b"three")));
+            wal.clear().unwrap();
+        }
+        assert!(Wal::open(&wal_path).unwrap().1.is_empty());
+
+        fs::write(&wal_path, "nonsense here\n").unwrap();
+        assert!(Wal::open(&wal_path).is_err());
+
+        fs::remove_file(&wal_path).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+/* Watching a directory tree for files to insert
+*
+*  inotify only watches single directories, so every directory under the
+*  one asked for gets a watch of its own, including any that appear later.
+*  A file is ready once it's been closed after being written to, or moved
+*  in whole.  Files in a directory that appears are ready as soon as it's
+*  seen, since they may have been written before it could be watched.  If
+*  the kernel's queue of events overflows, some were lost, so the whole tree
+*  is scanned again.  Only the files changed since the queue was last seen
+*  empty can be the ones lost, so only they're ready: their ctime says,
+*  which unlike their mtime is changed by moving them in too.
+*/
+use inotify::EventMask;
+use inotify::Inotify;
+use inotify::WatchDescriptor;
+use inotify::WatchMask;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// How far a file's ctime can be behind the clock when it's changed, as
+/// filesystems keep coarser time
+const CTIME_SLACK: Duration = Duration::from_secs(1);
+
+
+pub struct TreeWatcher {
+    inotify: Inotify,
+    /// The directory asked for
+    root: PathBuf,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    buffer: Vec<u8>,
+    /// When the queue of events was last found empty, so every change
+    /// before then has been seen
+    caught_up: SystemTime,
+}
+
+
+impl TreeWatcher {
+    /// Watch `dir` and everything under it
+    pub fn new(dir: &Path) -> Result<TreeWatcher, String> {
+        let inotify = Inotify::init().map_err(|err| format!("Unable to start inotify ({})", err))?;
+        let mut watcher = TreeWatcher {
+            inotify,
+            root: dir.to_owned(),
+            dirs: HashMap::new(),
+            buffer: vec![0; 64 * 1024],
+            caught_up: SystemTime::now(),
+        };
+        watcher.watch_tree(dir)?;
+        Ok(watcher)
+    }
+
+
+    /// Watch `dir` and every directory under it, returning the files already
+    /// in them
+    fn watch_tree(&mut self, dir: &Path) -> Result<Vec<PathBuf>, String> {
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::ONLYDIR;
+        let wd = self.inotify.watches().add(dir, mask)
+            .map_err(|err| format!("Unable to watch '{}' ({})", dir.display(), err))?;
+        self.dirs.insert(wd, dir.to_owned());
+
+        let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+            Err(err) => {
+                return Err(format!("Unable to list '{}' ({})", dir.display(), err));
+            },
+        };
+        entries.sort();
+
+        let mut files = Vec::new();
+        for path in entries {
+            match fs::symlink_metadata(&path).map(|metadata| metadata.file_type()) {
+                Ok(file_type) if file_type.is_dir() => files.extend(self.watch_tree(&path)?),
+                Ok(file_type) if file_type.is_file() => files.push(path),
+                /* Gone already, or not a file */
+                _ => {},
+            }
+        }
+        Ok(files)
+    }
+
+
+    /// The files that have become ready since the last call, without
+    /// waiting for any
+    pub fn ready(&mut self) -> Result<Vec<PathBuf>, String> {
+        self.read_ready(false)
+    }
+
+
+    /// Like `ready`, but waiting until something's happened in the tree
+    /// first.  That needn't have made any files ready.
+    pub fn wait(&mut self) -> Result<Vec<PathBuf>, String> {
+        self.read_ready(true)
+    }
+
+
+    fn read_ready(&mut self, block: bool) -> Result<Vec<PathBuf>, String> {
+        let mut ready = Vec::new();
+        let mut block = block;
+        loop {
+            let reading = SystemTime::now();
+            let events = if block {
+                self.inotify.read_events_blocking(&mut self.buffer)
+            }
+            else {
+                self.inotify.read_events(&mut self.buffer)
+            };
+            /* Only wait for the first lot */
+            block = false;
+            let events = match events {
+                Ok(events) => events,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.caught_up = reading;
+                    return Ok(ready);
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => {
+                    return Ok(ready);
+                },
+                Err(err) => {
+                    return Err(format!("Unable to read inotify events ({})", err));
+                },
+            };
+
+            let mut new_dirs = Vec::new();
+            let mut overflowed = false;
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflowed = true;
+                    continue;
+                }
+                if event.mask.contains(EventMask::IGNORED) {
+                    /* The directory's gone */
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                let path = match (self.dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => dir.join(name),
+                    _ => continue,
+                };
+                if event.mask.contains(EventMask::ISDIR) {
+                    new_dirs.push(path);
+                }
+                else if event.mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) {
+                    ready.push(path);
+                }
+            }
+            if overflowed {
+                /* Watching a directory again only updates its watch */
+                let root = self.root.clone();
+                let since = self.caught_up - CTIME_SLACK;
+                ready.extend(self.watch_tree(&root)?.into_iter().filter(|path| changed_since(path, since)));
+                continue;
+            }
+            for dir in new_dirs {
+                /* It may be gone again already, which is fine */
+                if dir.is_dir() {
+                    ready.extend(self.watch_tree(&dir)?);
+                }
+            }
+        }
+    }
+}
+
+
+/* Has the file at `path` been written to or moved since `time`?  Ones that
+ * can't be told are taken to have been. */
+fn changed_since(path: &Path, time: SystemTime) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            let ctime = UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+            ctime >= time
+        },
+        Err(_) => true,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_watcher() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bloom-cli-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("before"), b"not ready").unwrap();
+
+        let mut watcher = TreeWatcher::new(&dir).unwrap();
+        assert_eq!(watcher.ready(), Ok(vec![]));
+
+        fs::write(dir.join("a"), b"known").unwrap();
+        fs::write(dir.join("sub/b"), b"known").unwrap();
+        fs::write(std::env::temp_dir().join(format!("bloom-cli-watch-c-{}", std::process::id())), b"c").unwrap();
+        fs::rename(
+            std::env::temp_dir().join(format!("bloom-cli-watch-c-{}", std::process::id())),
+            dir.join("c")
+        ).unwrap();
+        /* Files in new directories are ready whenever they were written */
+        fs::create_dir_all(dir.join("new/deeper")).unwrap();
+        fs::write(dir.join("new/deeper/d"), b"known").unwrap();
+
+        let mut ready = watcher.ready().unwrap();
+        ready.sort();
+        ready.dedup();
+        assert_eq!(ready, vec![dir.join("a"), dir.join("c"), dir.join("new/deeper/d"), dir.join("sub/b")]);
+
+        fs::write(dir.join("new/deeper/e"), b"known").unwrap();
+        assert_eq!(watcher.wait(), Ok(vec![dir.join("new/deeper/e")]));
+        assert_eq!(watcher.ready(), Ok(vec![]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_rescans() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("bloom-cli-watch-overflow-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        /* Files that were seen before the overflow aren't ready again */
+        let mut watcher = TreeWatcher::new(&dir).unwrap();
+        fs::write(dir.join("seen"), b"known").unwrap();
+        std::thread::sleep(CTIME_SLACK + Duration::from_millis(100));
+        assert_eq!(watcher.ready(), Ok(vec![dir.join("seen")]));
+
+        /* Two events a file, more than the kernel queues by default */
+        let mut written: Vec<PathBuf> = (0..10_000).map(|i| dir.join(i.to_string())).collect();
+        for path in written.iter() {
+            fs::write(path, b"known").unwrap();
+        }
+        let mut ready = watcher.ready().unwrap();
+        ready.sort();
+        ready.dedup();
+        written.sort();
+        assert_eq!(ready, written);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -192,3 +192,645 @@ set +e
 result=$?
 [[ $result -eq 14 ]] || exit
 [[ -f "$tmp"/filter-12 ]] || exit 1
+
+# Don't insert while another process holds the filter's lock
+set -e
+rm -f "$tmp"/filter-13
+[[ ! -e "$tmp"/filter-13 ]] || exit 1
+"$exe" -x "$tmp"/filter-13
+[[ -f "$tmp"/filter-13 ]] || exit 1
+set +e
+flock "$tmp"/filter-13.lock "$exe" -x "$tmp"/filter-13 -i "$beefs" --no-wait >/dev/null
+result=$?
+set -e
+[[ $result -eq 18 ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-13 -q "$beefs") = "NOT IN" ]] || exit 1
+set +e
+"$exe" -x "$tmp"/filter-13 -i "$beefs" --wait --no-wait >/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Simultaneous inserts both land
+"$exe" -x "$tmp"/filter-13 -i "$beefs" &
+"$exe" -x "$tmp"/filter-13 -i "$deadbeef" &
+wait
+[[ $("$exe" -x "$tmp"/filter-13 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-13 -q "$deadbeef") = "IN" ]] || exit 1
+
+# Verify intact filters, notice corrupted ones
+rm -f "$tmp"/filter-14
+[[ ! -e "$tmp"/filter-14 ]] || exit 1
+"$exe" -x "$tmp"/filter-14 -i "$beefs"
+[[ -f "$tmp"/filter-14 ]] || exit 1
+[[ $("$exe" verify -x "$tmp"/filter-14) = "OK "* ]] || exit 1
+printf '\x01' | dd of="$tmp"/filter-14 bs=1 seek=1000 conv=notrunc status=none
+set +e
+"$exe" verify -x "$tmp"/filter-14 >/dev/null
+result=$?
+set -e
+[[ $result -eq 19 ]] || exit 1
+set +e
+"$exe" -x "$tmp"/filter-14 --check -q "$beefs" >/dev/null
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
+# Other queries read only the words they need, so leave the checksum to verify
+[[ $("$exe" -x "$tmp"/filter-14 -q "$beefs") = "IN" ]] || exit 1
+set +e
+"$exe" -x "$tmp"/filter-14 --check -i "$deadbeef" >/dev/null
+result=$?
+set -e
+[[ $result -eq 5 ]] || exit 1
+
+# Verify notices a filter that's the wrong length
+rm -f "$tmp"/filter-15
+"$exe" -x "$tmp"/filter-15
+truncate -s -8 "$tmp"/filter-15
+set +e
+"$exe" verify -x "$tmp"/filter-15 >/dev/null
+result=$?
+set -e
+[[ $result -eq 19 ]] || exit 1
+
+# Truncated filters are errors, not panics
+rm -f "$tmp"/filter-16
+head -c 4000 /dev/zero > "$tmp"/filter-16
+set +e
+"$exe" -x "$tmp"/filter-16 -q "$beefs" >/dev/null
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
+set +e
+"$exe" -x "$tmp"/filter-16 -i "$beefs" >/dev/null
+result=$?
+set -e
+[[ $result -eq 5 ]] || exit 1
+
+# Mapped and buffered access agree and can be mixed
+rm -f "$tmp"/filter-17
+"$exe" -x "$tmp"/filter-17
+"$exe" -x "$tmp"/filter-17 -i "$beefs"
+[[ $("$exe" -x "$tmp"/filter-17 -q "$beefs" --no-mmap) = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-17 -q "$deadbeef" --no-mmap) = "NOT IN" ]] || exit 1
+"$exe" -x "$tmp"/filter-17 -i "$deadbeef" --no-mmap
+[[ $("$exe" -x "$tmp"/filter-17 -q "$deadbeef") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-17 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" verify -x "$tmp"/filter-17) = "OK "* ]] || exit 1
+
+# Legacy filters without a header get upgraded by an insert
+rm -f "$tmp"/filter-18
+head -c $((51906 * 8)) /dev/zero > "$tmp"/filter-18
+[[ $("$exe" verify -x "$tmp"/filter-18) = "OK (legacy"* ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-18 -q "$beefs") = "NOT IN" ]] || exit 1
+"$exe" -x "$tmp"/filter-18 -i "$beefs"
+[[ $("$exe" verify -x "$tmp"/filter-18) = "OK (version"* ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-18 -q "$beefs") = "IN" ]] || exit 1
+
+# Inserts and queries go through a server when there is one
+rm -f "$tmp"/filter-19 "$tmp"/filter-20 "$tmp"/sock-1
+"$exe" -x "$tmp"/filter-19
+"$exe" -x "$tmp"/filter-20
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-19 --flush-interval 1 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-1 ]] && break; sleep 0.1; done
+[[ -S "$tmp"/sock-1 ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-19 -q "$beefs" --socket "$tmp"/sock-1) = "NOT IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-19 -i "$beefs" --socket "$tmp"/sock-1) = "" ]] || exit 1
+[[ $(BLOOM_CLI_SOCKET="$tmp"/sock-1 "$exe" -x "$tmp"/filter-19 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-19 -q "$deadbeef" --socket "$tmp"/sock-1) = "NOT IN" ]] || exit 1
+
+# ...and reach the disk soon after
+for _ in $(seq 50); do
+    [[ $("$exe" -x "$tmp"/filter-19 -q "$beefs") = "IN" ]] && break
+    sleep 0.1
+done
+[[ $("$exe" -x "$tmp"/filter-19 -q "$beefs") = "IN" ]] || exit 1
+
+# Filters the server doesn't hold are used directly
+[[ $("$exe" -x "$tmp"/filter-20 -i "$deadbeef" --socket "$tmp"/sock-1) = "" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-20 -q "$deadbeef" --socket "$tmp"/sock-1) = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-20 -q "$deadbeef") = "IN" ]] || exit 1
+
+# Only one server per socket
+set +e
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-20 >/dev/null
+result=$?
+set -e
+[[ $result -eq 20 ]] || exit 1
+
+# Without the server things work the same, straight from disk
+kill $server
+wait $server || true
+[[ $("$exe" -x "$tmp"/filter-19 -q "$beefs" --socket "$tmp"/sock-1) = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-19 -i "$deadbeef" --socket "$tmp"/sock-1) = "" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-19 -q "$deadbeef" --socket "$tmp"/sock-1) = "IN" ]] || exit 1
+[[ ! -e "$tmp"/sock-1 ]] || exit 1
+
+# A server that's killed outright loses nothing it said OK to, thanks to its
+# write-ahead log
+rm -f "$tmp"/filter-21 "$tmp"/wal-1
+"$exe" -x "$tmp"/filter-21
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-21 --wal "$tmp"/wal-1 --flush-interval 3600 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-1 ]] && break; sleep 0.1; done
+[[ $("$exe" -x "$tmp"/filter-21 -i "$beefs" --socket "$tmp"/sock-1) = "" ]] || exit 1
+kill -9 $server
+wait $server || true
+rm "$tmp"/sock-1
+[[ $("$exe" -x "$tmp"/filter-21 -q "$beefs") = "NOT IN" ]] || exit 1
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-21 --wal "$tmp"/wal-1 --flush-interval 3600 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-1 ]] && break; sleep 0.1; done
+[[ $("$exe" -x "$tmp"/filter-21 -q "$beefs" --socket "$tmp"/sock-1) = "IN" ]] || exit 1
+
+# ...and one that's asked to stop writes everything out first
+[[ $("$exe" -x "$tmp"/filter-21 -i "$deadbeef" --socket "$tmp"/sock-1) = "" ]] || exit 1
+kill $server
+wait $server
+[[ $("$exe" -x "$tmp"/filter-21 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-21 -q "$deadbeef") = "IN" ]] || exit 1
+[[ ! -s "$tmp"/wal-1 ]] || exit 1
+
+# A server killed part way through flushing leaves the filter whole, and
+# replays its log to get back anything that hadn't made it
+rm -f "$tmp"/filter-44 "$tmp"/wal-2
+"$exe" -x "$tmp"/filter-44
+for delay in 0 0.001 0.005 0.01 0.02 0.05; do
+    "$exe" serve --socket "$tmp"/sock-2 -x "$tmp"/filter-44 --wal "$tmp"/wal-2 &
+    server=$!
+    for _ in $(seq 50); do [[ -S "$tmp"/sock-2 ]] && break; sleep 0.1; done
+    [[ $("$exe" -x "$tmp"/filter-44 -i "$beefs" --socket "$tmp"/sock-2) = "" ]] || exit 1
+    kill $server
+    sleep $delay
+    kill -9 $server 2>/dev/null || true
+    wait $server || true
+    rm -f "$tmp"/sock-2
+    [[ $("$exe" verify -x "$tmp"/filter-44) = "OK "* ]] || exit 1
+done
+"$exe" serve --socket "$tmp"/sock-2 -x "$tmp"/filter-44 --wal "$tmp"/wal-2 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-2 ]] && break; sleep 0.1; done
+kill $server
+wait $server
+[[ $("$exe" -x "$tmp"/filter-44 -q "$beefs") = "IN" ]] || exit 1
+[[ ! -s "$tmp"/wal-2 ]] || exit 1
+
+# JSON output for results and errors
+rm -f "$tmp"/filter-22
+[[ $("$exe" --format json -x "$tmp"/filter-22 -i "$beefs") = '{"filter":"'"$tmp"'/filter-22","path":"'"$beefs"'","result":"INSERTED"}' ]] || exit 1
+[[ $("$exe" --format json -x "$tmp"/filter-22 -q "$beefs") = '{"filter":"'"$tmp"'/filter-22","path":"'"$beefs"'","result":"IN"}' ]] || exit 1
+[[ $("$exe" --format json -x "$tmp"/filter-22 -q "$deadbeef") = *'"result":"NOT IN"}' ]] || exit 1
+[[ $("$exe" --format json --show-bits -x "$tmp"/filter-22 -q "$beefs") = '{"bits":[2069134,2863305,819620,1247752,3250321,2268504,1247576,1047824],'* ]] || exit 1
+[[ $("$exe" --format json verify -x "$tmp"/filter-22) = '{"checksum":"72f357a122949fbc","filter":"'"$tmp"'/filter-22","k":8,"m":3321928,"result":"OK","version":1}' ]] || exit 1
+set +e
+output=$("$exe" --format json -x "$tmp"/filter-22 -q "$tmp"/nonexistent 2>&1 >/dev/null)
+result=$?
+set -e
+[[ $result -eq 2 ]] || exit 1
+[[ $output = '{"error":"Cannot access file '"'$tmp"'/nonexistent'"'"'","exit_code":2}' ]] || exit 1
+
+# Quietly, the exit status says whether it's in, like grep -q
+rm -f "$tmp"/filter-23
+"$exe" -x "$tmp"/filter-23 -i "$beefs"
+[[ $("$exe" -s -x "$tmp"/filter-23 -q "$beefs") = "" ]] || exit 1
+set +e
+"$exe" -s -x "$tmp"/filter-23 -q "$deadbeef"
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+set +e
+"$exe" -s -x "$tmp"/filter-23 -q "$tmp"/nonexistent >/dev/null
+result=$?
+set -e
+[[ $result -eq 2 ]] || exit 1
+set +e
+"$exe" -s -x "$tmp"/filter-23 -q "$beefs" --bogus 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Several queries at once, needing all or any of them to be in
+[[ $("$exe" -x "$tmp"/filter-23 -q "$beefs" -q "$deadbeef") = "$beefs: IN
+$deadbeef: NOT IN" ]] || exit 1
+set +e
+"$exe" -s -x "$tmp"/filter-23 -q "$beefs" -q "$deadbeef"
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+"$exe" -s --any -x "$tmp"/filter-23 -q "$beefs" -q "$deadbeef"
+set +e
+"$exe" --any -x "$tmp"/filter-23 -q "$beefs" -q "$deadbeef" >/dev/null 2>&1
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+"$exe" -s -x "$tmp"/filter-23 -q "$beefs" -q "$beefs"
+[[ $("$exe" --format json -x "$tmp"/filter-23 -q "$beefs" -q "$deadbeef" | wc -l) -eq 2 ]] || exit 1
+
+# Test-and-insert in one step
+rm -f "$tmp"/filter-24
+[[ $("$exe" add-if-absent -x "$tmp"/filter-24 -i "$beefs") = "ADDED" ]] || exit 1
+set +e
+output=$("$exe" add-if-absent -x "$tmp"/filter-24 -i "$beefs")
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+[[ $output = "ALREADY IN" ]] || exit 1
+[[ $("$exe" --no-mmap add-if-absent -s -x "$tmp"/filter-24 -i "$deadbeef") = "" ]] || exit 1
+set +e
+"$exe" --no-mmap add-if-absent -s -x "$tmp"/filter-24 -i "$deadbeef"
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+"$exe" verify -x "$tmp"/filter-24 >/dev/null
+
+# ...including through a server
+rm -f "$tmp"/filter-25
+"$exe" -x "$tmp"/filter-25
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-25 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-1 ]] && break; sleep 0.1; done
+[[ $("$exe" --socket "$tmp"/sock-1 add-if-absent -x "$tmp"/filter-25 -i "$beefs") = "ADDED" ]] || exit 1
+set +e
+"$exe" --socket "$tmp"/sock-1 add-if-absent -s -x "$tmp"/filter-25 -i "$beefs"
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+kill $server
+wait $server
+[[ $("$exe" -x "$tmp"/filter-25 -q "$beefs") = "IN" ]] || exit 1
+
+# Errors and diagnostics go to stderr, leaving stdout for results
+rm -f "$tmp"/filter-26
+"$exe" -x "$tmp"/filter-26 -i "$beefs"
+[[ $("$exe" -x "$tmp"/filter-26 -q "$tmp"/nonexistent 2>/dev/null) = "" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-26 -q "$tmp"/nonexistent 2>&1) = "Cannot access file '$tmp/nonexistent'" ]] || exit 1
+[[ $("$exe" -s -x "$tmp"/filter-26 -q "$tmp"/nonexistent 2>&1) = "" ]] || exit 1
+[[ $("$exe" -v -x "$tmp"/filter-26 -q "$beefs" 2>/dev/null) = "IN" ]] || exit 1
+[[ $("$exe" -vv -x "$tmp"/filter-26 -q "$beefs" 2>/dev/null) = "IN" ]] || exit 1
+[[ $("$exe" -vv -x "$tmp"/filter-26 -q "$beefs" 2>&1 >/dev/null) = *"picking bits [2069134, 2863305, 819620, 1247752, 3250321, 2268504, 1247576, 1047824]"* ]] || exit 1
+[[ $("$exe" -v -x "$tmp"/filter-26 -q "$beefs" 2>&1 >/dev/null) = "" ]] || exit 1
+
+# SHA-256 digests of files land on the same bits as digest lists of them
+rm -f "$tmp"/filter-27 "$tmp"/filter-28
+"$exe" --view sha256 -x "$tmp"/filter-27 -i "$beefs"
+sha256sum "$beefs" > "$tmp"/digests-1
+[[ $("$exe" --digest-list -x "$tmp"/filter-27 -q "$tmp"/digests-1) = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-27 -q "$beefs") = "NOT IN" ]] || exit 1
+sha256sum "$beefs" "$deadbeef" > "$tmp"/digests-2
+"$exe" --digest-list -x "$tmp"/filter-28 -i "$tmp"/digests-2
+[[ $("$exe" --view sha256 -x "$tmp"/filter-28 -q "$deadbeef") = "IN" ]] || exit 1
+[[ $("$exe" --digest-list -x "$tmp"/filter-27 -q "$tmp"/digests-2) = "$beefs: IN
+$deadbeef: NOT IN" ]] || exit 1
+echo "not a digest  $beefs" > "$tmp"/digests-3
+set +e
+"$exe" --digest-list -x "$tmp"/filter-27 -q "$tmp"/digests-3 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 22 ]] || exit 1
+
+# Metadata views tell versions of a file apart without reading it
+rm -f "$tmp"/filter-29
+cp "$beefs" "$tmp"/beefs-copy
+touch -d @1000000000 "$tmp"/beefs-copy
+"$exe" --view path,size,mtime -x "$tmp"/filter-29 -i "$tmp"/beefs-copy
+[[ $("$exe" --view mtime,size,path -x "$tmp"/filter-29 -q "$tmp"/beefs-copy) = "IN" ]] || exit 1
+[[ $("$exe" --view size,mtime -x "$tmp"/filter-29 -q "$tmp"/beefs-copy) = "NOT IN" ]] || exit 1
+touch -d @1000000001 "$tmp"/beefs-copy
+[[ $("$exe" --view path,size,mtime -x "$tmp"/filter-29 -q "$tmp"/beefs-copy) = "NOT IN" ]] || exit 1
+set +e
+"$exe" --view path,md5 -x "$tmp"/filter-29 -q "$tmp"/beefs-copy 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Content-defined chunks find files that are mostly the same
+rm -f "$tmp"/filter-30
+seq 1 50000 > "$tmp"/chunky-1
+sed 's/^25000$/changed/' "$tmp"/chunky-1 > "$tmp"/chunky-2
+"$exe" --chunks -x "$tmp"/filter-30 -i "$tmp"/chunky-1
+[[ $("$exe" --chunks -x "$tmp"/filter-30 -q "$tmp"/chunky-1) = "1.000" ]] || exit 1
+[[ $("$exe" --chunks -x "$tmp"/filter-30 -q "$tmp"/chunky-2 -q "$beefs") = "$tmp/chunky-2: 0.971
+$beefs: 0.000" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-30 -q "$tmp"/chunky-1) = "NOT IN" ]] || exit 1
+[[ $("$exe" --chunks --format json -x "$tmp"/filter-30 -q "$tmp"/chunky-2) = *'"chunks":35,"chunks_in":34,'* ]] || exit 1
+"$exe" --chunks -s -x "$tmp"/filter-30 -q "$tmp"/chunky-1
+set +e
+"$exe" --chunks -s -x "$tmp"/filter-30 -q "$tmp"/chunky-2
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+
+# Filters can normalize text, and queries of them do the same
+rm -f "$tmp"/filter-31
+printf 'Some  Text\r\nhere \r\n' > "$tmp"/text-crlf
+printf 'some text\nhere\n' > "$tmp"/text-lf
+"$exe" --normalize line-endings,whitespace,case -x "$tmp"/filter-31 -i "$tmp"/text-crlf
+[[ $("$exe" -x "$tmp"/filter-31 -q "$tmp"/text-lf) = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-31 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $("$exe" verify -x "$tmp"/filter-31) = *"normalizing line-endings,whitespace,case)" ]] || exit 1
+[[ $("$exe" --normalize case,whitespace,line-endings -x "$tmp"/filter-31 -q "$tmp"/text-lf) = "IN" ]] || exit 1
+set +e
+"$exe" --normalize case -x "$tmp"/filter-31 -q "$tmp"/text-lf 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --view sha256 -x "$tmp"/filter-31 -q "$tmp"/text-lf 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+"$exe" serve --socket "$tmp"/sock-1 -x "$tmp"/filter-31 &
+server=$!
+for _ in $(seq 50); do [[ -S "$tmp"/sock-1 ]] && break; sleep 0.1; done
+printf 'MORE text\n' > "$tmp"/text-more
+"$exe" --socket "$tmp"/sock-1 -x "$tmp"/filter-31 -i "$tmp"/text-more
+kill $server
+wait $server
+printf 'more   TEXT\r\n' > "$tmp"/text-more
+[[ $("$exe" -x "$tmp"/filter-31 -q "$tmp"/text-more) = "IN" ]] || exit 1
+"$exe" verify -x "$tmp"/filter-31 >/dev/null
+
+# Each file in a tar or zip archive is an item of its own
+rm -rf "$tmp"/filter-32 "$tmp"/release "$tmp"/release.tar.gz "$tmp"/release.zip
+mkdir -p "$tmp"/release/src
+cp "$beefs" "$tmp"/release/src/beefs
+cp "$deadbeef" "$tmp"/release/deadbeef
+tar -czf "$tmp"/release.tar.gz -C "$tmp" release
+"$exe" --archive -x "$tmp"/filter-32 -i "$tmp"/release.tar.gz
+[[ $("$exe" -x "$tmp"/filter-32 -q "$beefs") = "IN" ]] || exit 1
+(cd "$tmp" && zip -qr release.zip release)
+echo "new" > "$tmp"/release/new
+(cd "$tmp" && zip -qr release.zip release/new)
+[[ $("$exe" --archive -x "$tmp"/filter-32 -q "$tmp"/release.zip | sort) = "$tmp/release.zip:release/deadbeef: IN
+$tmp/release.zip:release/new: NOT IN
+$tmp/release.zip:release/src/beefs: IN" ]] || exit 1
+set +e
+"$exe" --archive -x "$tmp"/filter-32 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 23 ]] || exit 1
+
+# Finding duplicates in a directory tree
+rm -rf "$tmp"/tree
+mkdir -p "$tmp"/tree/sub
+cp "$beefs" "$tmp"/tree/a
+cp "$deadbeef" "$tmp"/tree/b
+cp "$beefs" "$tmp"/tree/sub/c
+ln -s "$tmp"/tree/a "$tmp"/tree/link
+[[ $("$exe" dupes "$tmp"/tree) = "$tmp/tree/sub/c" ]] || exit 1
+[[ $("$exe" dupes --confirm "$tmp"/tree) = "$tmp/tree/sub/c: $tmp/tree/a" ]] || exit 1
+[[ $("$exe" --format json dupes --confirm "$tmp"/tree) = "{\"duplicate_of\":\"$tmp/tree/a\",\"path\":\"$tmp/tree/sub/c\",\"result\":\"DUPLICATE\"}" ]] || exit 1
+"$exe" -s dupes "$tmp"/tree
+rm "$tmp"/tree/sub/c
+set +e
+"$exe" -s dupes "$tmp"/tree
+result=$?
+set -e
+[[ $result -eq 1 ]] || exit 1
+set +e
+"$exe" dupes "$tmp"/nonexistent 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 2 ]] || exit 1
+
+# Watching a directory inserts files as they're written
+rm -rf "$tmp"/inbox
+mkdir -p "$tmp"/inbox/sub
+"$exe" watch "$tmp"/inbox -x "$tmp"/inbox/filter-33 &
+watcher=$!
+for _ in $(seq 50); do [[ -f "$tmp"/inbox/filter-33 ]] && break; sleep 0.1; done
+sleep 0.5
+cp "$beefs" "$tmp"/inbox/sub/beefs
+for _ in $(seq 50); do
+    [[ $("$exe" -x "$tmp"/inbox/filter-33 -q "$beefs") = "IN" ]] && break
+    sleep 0.1
+done
+[[ $("$exe" -x "$tmp"/inbox/filter-33 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/inbox/filter-33 -q "$deadbeef") = "NOT IN" ]] || exit 1
+kill $watcher
+wait $watcher
+
+# ...and anything still waiting to go in when it's stopped
+"$exe" watch --batch-interval 60 "$tmp"/inbox -x "$tmp"/inbox/filter-33 &
+watcher=$!
+sleep 0.5
+cp "$deadbeef" "$tmp"/inbox/deadbeef
+sleep 0.5
+kill $watcher
+wait $watcher
+[[ $("$exe" -x "$tmp"/inbox/filter-33 -q "$deadbeef") = "IN" ]] || exit 1
+"$exe" verify -x "$tmp"/inbox/filter-33 >/dev/null
+
+# Every blob in a git repository's history, by contents or object ID
+rm -rf "$tmp"/repo
+mkdir -p "$tmp"/repo
+git -C "$tmp"/repo init -q
+cp "$beefs" "$tmp"/repo/file
+git -C "$tmp"/repo add file
+git -C "$tmp"/repo -c user.name=test -c user.email=test@example.com commit -q -m first
+cp "$deadbeef" "$tmp"/repo/file
+git -C "$tmp"/repo -c user.name=test -c user.email=test@example.com commit -q -a -m second
+echo "never committed" > "$tmp"/repo/file
+rm -f "$tmp"/filter-34 "$tmp"/filter-35
+"$exe" git-index "$tmp"/repo -x "$tmp"/filter-34
+[[ $("$exe" -x "$tmp"/filter-34 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-34 -q "$deadbeef") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-34 -q "$tmp"/repo/file) = "NOT IN" ]] || exit 1
+"$exe" --view git-oid git-index "$tmp"/repo/.git -x "$tmp"/filter-35
+[[ $("$exe" --view git-oid -x "$tmp"/filter-35 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" --view git-oid -x "$tmp"/filter-35 -q "$tmp"/repo/file) = "NOT IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-35 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $("$exe" --format json git-index "$tmp"/repo -x "$tmp"/filter-34 | wc -l) -eq 2 ]] || exit 1
+set +e
+"$exe" git-index "$tmp"/tree -x "$tmp"/filter-34 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 25 ]] || exit 1
+set +e
+"$exe" --view size git-index "$tmp"/repo -x "$tmp"/filter-34 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Rotating filters forget items once their generation's dropped
+rm -f "$tmp"/filter-36
+"$exe" --generations 2 -x "$tmp"/filter-36 -i "$beefs"
+[[ $("$exe" verify -x "$tmp"/filter-36) = *", rotating 2 generations when asked)" ]] || exit 1
+[[ $("$exe" --format json verify -x "$tmp"/filter-36) = *'"generations":2'* ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-36 -q "$beefs") = "IN" ]] || exit 1
+"$exe" rotate -x "$tmp"/filter-36
+"$exe" -x "$tmp"/filter-36 -i "$deadbeef"
+[[ $("$exe" -x "$tmp"/filter-36 -q "$beefs") = "IN" ]] || exit 1
+"$exe" rotate -x "$tmp"/filter-36
+[[ $("$exe" -x "$tmp"/filter-36 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-36 -q "$deadbeef") = "IN" ]] || exit 1
+[[ $("$exe" add-if-absent -x "$tmp"/filter-36 -i "$deadbeef") = "ALREADY IN" ]] || exit 1
+"$exe" rotate -x "$tmp"/filter-36
+"$exe" rotate -x "$tmp"/filter-36
+[[ $("$exe" -x "$tmp"/filter-36 -q "$deadbeef") = "NOT IN" ]] || exit 1
+"$exe" verify -x "$tmp"/filter-36 >/dev/null
+set +e
+"$exe" rotate -x "$tmp"/filter-1 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --generations 3 -x "$tmp"/filter-36 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" serve --socket "$tmp"/rotating.sock -x "$tmp"/filter-36 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
+
+# ...or once enough time's passed, without anything being written
+rm -f "$tmp"/filter-37
+"$exe" --generations 1 --rotate-interval 1 -x "$tmp"/filter-37 -i "$beefs"
+before=$(sha256sum < "$tmp"/filter-37)
+sleep 2
+[[ $("$exe" -x "$tmp"/filter-37 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $(sha256sum < "$tmp"/filter-37) = "$before" ]] || exit 1
+
+# Stable filters keep new items in however many go in, forgetting old ones
+rm -f "$tmp"/filter-38
+"$exe" --stable-decay 10 -x "$tmp"/filter-38 -i "$beefs"
+[[ $("$exe" verify -x "$tmp"/filter-38) = *", stable, counters up to 7, 10 decrements per insert)" ]] || exit 1
+[[ $("$exe" --format json verify -x "$tmp"/filter-38) = *'"stable_decay":10'* ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-38 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-38 -q "$deadbeef") = "NOT IN" ]] || exit 1
+[[ $("$exe" add-if-absent -x "$tmp"/filter-38 -i "$deadbeef") = "ADDED" ]] || exit 1
+[[ $("$exe" add-if-absent -x "$tmp"/filter-38 -i "$deadbeef") = "ALREADY IN" ]] || exit 1
+"$exe" verify -x "$tmp"/filter-38 >/dev/null
+set +e
+"$exe" --stable-decay 10 --stable-max 3 -x "$tmp"/filter-38 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --stable-max 3 -x "$tmp"/filter-38 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --stable-decay 10 --generations 2 -x "$tmp"/filter-38 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --stable-decay 10 --stable-max 16 -x "$tmp"/filter-39 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Expiring filters forget items their TTL after they went in...
+rm -f "$tmp"/filter-39
+"$exe" --ttl 5 -x "$tmp"/filter-39 -i "$beefs"
+[[ $("$exe" -x "$tmp"/filter-39 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-39 -q "$deadbeef") = "NOT IN" ]] || exit 1
+[[ $("$exe" verify -x "$tmp"/filter-39) = *", expiring items after 5s by default)" ]] || exit 1
+[[ $("$exe" --format json verify -x "$tmp"/filter-39) = *'"ttl":5'* ]] || exit 1
+before=$(sha256sum < "$tmp"/filter-39)
+# ...which each insert can set for its own items
+rm -f "$tmp"/filter-41
+"$exe" --ttl 5 -x "$tmp"/filter-41 -i "$beefs"
+"$exe" --ttl 3600 -x "$tmp"/filter-41 -i "$deadbeef"
+sleep 6
+[[ $("$exe" -x "$tmp"/filter-39 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $(sha256sum < "$tmp"/filter-39) = "$before" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-41 -q "$beefs") = "NOT IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-41 -q "$deadbeef") = "IN" ]] || exit 1
+[[ $("$exe" add-if-absent -x "$tmp"/filter-39 -i "$deadbeef") = "ADDED" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-39 -q "$deadbeef") = "IN" ]] || exit 1
+
+# ...and compacting clears them on disk
+"$exe" compact -x "$tmp"/filter-39
+[[ $("$exe" -x "$tmp"/filter-39 -q "$deadbeef") = "IN" ]] || exit 1
+"$exe" verify -x "$tmp"/filter-39 >/dev/null
+sleep 6
+"$exe" compact -x "$tmp"/filter-39
+[[ $(tail -c +65 "$tmp"/filter-39 | tr -d '\0' | wc -c) -eq 0 ]] || exit 1
+"$exe" verify -x "$tmp"/filter-39 >/dev/null
+set +e
+"$exe" compact -x "$tmp"/filter-1 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --ttl 60 -x "$tmp"/filter-1 -i "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --ttl 0 -x "$tmp"/filter-39 -i "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+set +e
+"$exe" --ttl 60 --stable-decay 10 -x "$tmp"/filter-39 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 17 ]] || exit 1
+
+# Queries that read the whole filter notice it's corrupt, ones that read only
+# what they need (the default) don't
+rm -f "$tmp"/filter-40
+"$exe" -x "$tmp"/filter-40 -i "$beefs"
+printf '\x01' | dd of="$tmp"/filter-40 bs=1 seek=1000 conv=notrunc status=none
+[[ $("$exe" -x "$tmp"/filter-40 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" --no-mmap -x "$tmp"/filter-40 -q "$beefs") = "IN" ]] || exit 1
+[[ $("$exe" -x "$tmp"/filter-40 -q "$beefs" -q "$deadbeef") = *": IN"*": NOT IN" ]] || exit 1
+set +e
+"$exe" --check -x "$tmp"/filter-40 -q "$beefs" >/dev/null 2>&1
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
+set +e
+"$exe" -x "$tmp"/filter-40 -q "$beefs" -q "$deadbeef" --no-mmap >/dev/null 2>&1
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
+set +e
+"$exe" --check -x "$tmp"/filter-40 -i "$deadbeef" >/dev/null 2>&1
+result=$?
+set -e
+[[ $result -eq 5 ]] || exit 1
+
+# Several add-if-absents creating the same filter at once all see it whole
+rm -f "$tmp"/filter-42
+pids=()
+for _ in 1 2 3 4 5 6 7 8; do
+    "$exe" add-if-absent -s -x "$tmp"/filter-42 -i "$beefs" &
+    pids+=($!)
+done
+added=0
+for pid in "${pids[@]}"; do
+    set +e
+    wait "$pid"
+    result=$?
+    set -e
+    [[ $result -le 1 ]] || exit 1
+    [[ $result -eq 0 ]] && added=$((added + 1))
+done
+[[ $added -eq 1 ]] || exit 1
+"$exe" verify -x "$tmp"/filter-42 >/dev/null
+[[ -z $(find "$tmp" -name '.filter-42.*') ]] || exit 1
+
+# -vv is only taken for -v -v where it's a flag, not an option's value
+printf 'vee vee' > "$tmp"/-vv
+(cd "$tmp" && "$exe" -vv -x filter-43 -i -vv 2>/dev/null)
+[[ $(cd "$tmp" && "$exe" -x filter-43 -q -vv) = "IN" ]] || exit 1
+
+# A filter whose header can't be read is an error, not one without normalizers
+set +e
+"$exe" add-if-absent -x "$tmp"/filter-16 -i "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 5 ]] || exit 1
+set +e
+"$exe" --normalize case -x "$tmp"/filter-16 -q "$beefs" 2>/dev/null
+result=$?
+set -e
+[[ $result -eq 16 ]] || exit 1
@@ -8,7 +8,7 @@ fn test_first() {
     path.push("functional-test.bash");
     let status = Command::new("bash").args([path.to_str().unwrap()]).status();
     assert!(status.is_ok());
-    if status.is_ok() {
-        assert!(status.unwrap().success());
+    if let Ok(status) = status {
+        assert!(status.success());
     }
 }